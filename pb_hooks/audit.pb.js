@@ -0,0 +1,9 @@
+/// <reference path="../pb_data/types.d.ts" />
+// admins bypass the collection rules, so changing or removing audit entries is refused for everyone here
+onModelBeforeUpdate((e) => {
+  throw new BadRequestError("Audit entries can't be changed.")
+}, "audit")
+
+onModelBeforeDelete((e) => {
+  throw new BadRequestError("Audit entries can't be deleted.")
+}, "audit")
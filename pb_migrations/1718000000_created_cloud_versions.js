@@ -0,0 +1,33 @@
+/// <reference path="../pb_data/types.d.ts" />
+// every stored blob of a cloud file, the record with an empty key describes the current file
+// and the others point to the archived blobs below .cloud/versions
+migrate((db) => {
+  const collection = new Collection({
+    name: "cloud_versions",
+    type: "base",
+    system: false,
+    schema: [
+      { name: "path", type: "text", required: true, options: { min: null, max: null, pattern: "" } },
+      { name: "key", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "size", type: "number", required: false, options: { min: 0, max: null, noDecimal: true } },
+      { name: "user", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "timestamp", type: "number", required: false, options: { min: null, max: null, noDecimal: true } },
+    ],
+    indexes: [
+      "CREATE INDEX `idx_cloud_versions_path` ON `cloud_versions` (`path`)",
+      "CREATE INDEX `idx_cloud_versions_key` ON `cloud_versions` (`key`)",
+    ],
+    // only the backend reads and writes them, with its admin connection
+    listRule: null,
+    viewRule: null,
+    createRule: null,
+    updateRule: null,
+    deleteRule: null,
+    options: {},
+  })
+
+  return Dao(db).saveCollection(collection)
+}, (db) => {
+  const dao = new Dao(db)
+  return dao.deleteCollection(dao.findCollectionByNameOrId("cloud_versions"))
+})
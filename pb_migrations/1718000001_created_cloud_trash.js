@@ -0,0 +1,32 @@
+/// <reference path="../pb_data/types.d.ts" />
+// deleted files and directories, key is where they were moved below .cloud/trash
+migrate((db) => {
+  const collection = new Collection({
+    name: "cloud_trash",
+    type: "base",
+    system: false,
+    schema: [
+      { name: "path", type: "text", required: true, options: { min: null, max: null, pattern: "" } },
+      { name: "key", type: "text", required: true, options: { min: null, max: null, pattern: "" } },
+      { name: "dir", type: "bool", required: false, options: {} },
+      { name: "user", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "timestamp", type: "number", required: false, options: { min: null, max: null, noDecimal: true } },
+    ],
+    indexes: [
+      "CREATE INDEX `idx_cloud_trash_user` ON `cloud_trash` (`user`)",
+      "CREATE INDEX `idx_cloud_trash_timestamp` ON `cloud_trash` (`timestamp`)",
+    ],
+    // only the backend reads and writes them, with its admin connection
+    listRule: null,
+    viewRule: null,
+    createRule: null,
+    updateRule: null,
+    deleteRule: null,
+    options: {},
+  })
+
+  return Dao(db).saveCollection(collection)
+}, (db) => {
+  const dao = new Dao(db)
+  return dao.deleteCollection(dao.findCollectionByNameOrId("cloud_trash"))
+})
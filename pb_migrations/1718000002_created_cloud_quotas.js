@@ -0,0 +1,29 @@
+/// <reference path="../pb_data/types.d.ts" />
+// storage limits, an empty user applies to everyone and an empty dir to the whole cloud
+migrate((db) => {
+  const collection = new Collection({
+    name: "cloud_quotas",
+    type: "base",
+    system: false,
+    schema: [
+      { name: "user", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "dir", type: "text", required: false, options: { min: null, max: null, pattern: "^[^/]*$" } },
+      { name: "limit", type: "number", required: true, options: { min: 0, max: null, noDecimal: true } },
+    ],
+    indexes: [
+      "CREATE INDEX `idx_cloud_quotas_user` ON `cloud_quotas` (`user`)",
+    ],
+    // only the backend reads and writes them, with its admin connection
+    listRule: null,
+    viewRule: null,
+    createRule: null,
+    updateRule: null,
+    deleteRule: null,
+    options: {},
+  })
+
+  return Dao(db).saveCollection(collection)
+}, (db) => {
+  const dao = new Dao(db)
+  return dao.deleteCollection(dao.findCollectionByNameOrId("cloud_quotas"))
+})
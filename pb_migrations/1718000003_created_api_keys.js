@@ -0,0 +1,34 @@
+/// <reference path="../pb_data/types.d.ts" />
+// keys users create for scripts, only the sha256 of the key itself is stored
+migrate((db) => {
+  const collection = new Collection({
+    name: "api_keys",
+    type: "base",
+    system: false,
+    schema: [
+      { name: "user", type: "text", required: true, options: { min: null, max: null, pattern: "" } },
+      { name: "name", type: "text", required: true, options: { min: 1, max: 100, pattern: "" } },
+      { name: "hash", type: "text", required: true, options: { min: 64, max: 64, pattern: "^[0-9a-f]+$" } },
+      { name: "permissions", type: "number", required: false, options: { min: 0, max: null, noDecimal: true } },
+      { name: "expires", type: "number", required: true, options: { min: null, max: null, noDecimal: true } },
+      { name: "last_used", type: "number", required: false, options: { min: null, max: null, noDecimal: true } },
+      { name: "scope", type: "json", required: false, options: { maxSize: 2000000 } },
+    ],
+    indexes: [
+      "CREATE UNIQUE INDEX `idx_api_keys_hash` ON `api_keys` (`hash`)",
+      "CREATE INDEX `idx_api_keys_user` ON `api_keys` (`user`)",
+    ],
+    // only the backend reads and writes them, with its admin connection
+    listRule: null,
+    viewRule: null,
+    createRule: null,
+    updateRule: null,
+    deleteRule: null,
+    options: {},
+  })
+
+  return Dao(db).saveCollection(collection)
+}, (db) => {
+  const dao = new Dao(db)
+  return dao.deleteCollection(dao.findCollectionByNameOrId("api_keys"))
+})
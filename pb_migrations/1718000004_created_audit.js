@@ -0,0 +1,35 @@
+/// <reference path="../pb_data/types.d.ts" />
+// who changed what, entries are only ever appended, pb_hooks/audit.pb.js keeps admins from changing them too
+migrate((db) => {
+  const collection = new Collection({
+    name: "audit",
+    type: "base",
+    system: false,
+    schema: [
+      { name: "actor", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "action", type: "text", required: true, options: { min: null, max: null, pattern: "" } },
+      { name: "target", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "before", type: "json", required: false, options: { maxSize: 2000000 } },
+      { name: "after", type: "json", required: false, options: { maxSize: 2000000 } },
+      { name: "ip", type: "text", required: false, options: { min: null, max: null, pattern: "" } },
+      { name: "timestamp", type: "number", required: true, options: { min: null, max: null, noDecimal: true } },
+    ],
+    indexes: [
+      "CREATE INDEX `idx_audit_actor` ON `audit` (`actor`)",
+      "CREATE INDEX `idx_audit_action` ON `audit` (`action`)",
+      "CREATE INDEX `idx_audit_timestamp` ON `audit` (`timestamp`)",
+    ],
+    // only the backend reads and writes them, with its admin connection, and there is no rule that allows a change
+    listRule: null,
+    viewRule: null,
+    createRule: null,
+    updateRule: null,
+    deleteRule: null,
+    options: {},
+  })
+
+  return Dao(db).saveCollection(collection)
+}, (db) => {
+  const dao = new Dao(db)
+  return dao.deleteCollection(dao.findCollectionByNameOrId("audit"))
+})
@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tide::Request;
+
+use crate::{audit, auth::Identity, cloud_path::CloudPath, db::{create_record, delete_record, get_collection_records, get_record, modify_record, new_record_id, Filter, ModifyRecord}, error::ApiError, permissions::{is_admin, has_permissions, request_identity, request_user, Permissions}, state::AppState};
+
+// keys are shown once and only their hash is stored, the prefix makes leaked keys easy to find
+const KEY_PREFIX: &str = "pdk_";
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ApiKey {
+  id: String,
+  user: String,
+  name: String,
+  hash: String,
+  permissions: i32,
+  expires: i64,
+  #[serde(default)]
+  last_used: i64,
+  #[serde(default)]
+  scope: Option<Scope>,
+}
+
+// limits a key to one subtree of the cloud, on top of the owner's access rules
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Scope {
+  pub(crate) path: String,
+  pub(crate) write: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiKeyCreate {
+  name: String,
+  #[serde(default)]
+  permissions: i32,
+  // expanded into permissions, the key only stores the bits
+  #[serde(default)]
+  roles: Vec<String>,
+  expires: i64,
+  scope: Option<Scope>,
+}
+
+#[derive(Serialize)]
+struct ApiKeyInfo {
+  id: String,
+  name: String,
+  permissions: i32,
+  expires: i64,
+  last_used: i64,
+  scope: Option<Scope>,
+}
+
+#[derive(Serialize)]
+struct ApiKeyCreated {
+  key: String,
+  #[serde(flatten)]
+  info: ApiKeyInfo,
+}
+
+#[derive(Serialize)]
+struct ApiKeyUsed {
+  id: String,
+  last_used: i64,
+}
+
+#[derive(Deserialize)]
+struct Owner {
+  permissions: i32,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+  fn from(key: ApiKey) -> Self {
+    ApiKeyInfo { id: key.id, name: key.name, permissions: key.permissions, expires: key.expires, last_used: key.last_used, scope: key.scope }
+  }
+}
+
+impl ModifyRecord for ApiKeyUsed {
+  fn id(&self) -> &String {
+    &self.id
+  }
+}
+
+pub(crate) async fn get_api_keys(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let user = request_user(&req)?;
+  let keys = get_collection_records::<ApiKey>(&state.db, "api_keys", Some(Filter::eq("user", user))).await?;
+  let keys: Vec<ApiKeyInfo> = keys.into_iter().map(ApiKeyInfo::from).collect();
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&keys)?).build())
+}
+
+pub(crate) async fn create_api_key(mut req: Request<AppState>) -> tide::Result {
+  // a leaked key must not be able to mint new ones that outlive it
+  if request_identity(&req).is_some_and(|i| i.api_key.is_some()) {
+    return Ok(tide::Response::new(403));
+  }
+
+  let ApiKeyCreate { name, permissions, roles, expires, scope } = req.body_json().await?;
+  let state = req.state();
+  let user = request_user(&req)?;
+  if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+    return Ok(ApiError::BadRequest(format!("name must have 1 to {} characters", MAX_NAME_LEN)).response());
+  }
+  if expires <= chrono::Utc::now().timestamp() {
+    return Ok(ApiError::BadRequest("expires must be in the future".to_string()).response());
+  }
+  let permissions = match Permissions::from_roles(&roles) {
+    Ok(p) if permissions >= 0 => p | Permissions::from_bits_retain(permissions),
+    Ok(_) => return Ok(ApiError::BadRequest("permissions must not be negative".to_string()).response()),
+    Err(e) => return Ok(e.response()),
+  };
+  // admins hold every permission, like everywhere else
+  if !has_permissions(&req, permissions) {
+    return Ok(ApiError::BadRequest("permissions must be a subset of your own".to_string()).response());
+  }
+  let scope = match scope {
+    Some(Scope { path, write }) => {
+      if permissions != Permissions::CLOUD {
+        return Ok(ApiError::BadRequest("scoped keys can only have the cloud permission".to_string()).response());
+      }
+      match CloudPath::parse(&path) {
+        Ok(path) => Some(Scope { path: path.to_string(), write }),
+        Err(e) => return Ok(e.response()),
+      }
+    },
+    None => None,
+  };
+
+  let key = format!("{}{}", KEY_PREFIX, hex::encode(rand::random::<[u8; 32]>()));
+  let api_key = ApiKey {
+    id: new_record_id(),
+    user: user.to_string(),
+    name,
+    hash: key_hash(&key),
+    permissions: permissions.bits(),
+    expires,
+    last_used: 0,
+    scope,
+  };
+  create_record(&state.db, "api_keys", &api_key).await?;
+  audit::record(&req, "key.create", &api_key.id, serde_json::Value::Null, serde_json::to_value(ApiKeyInfo::from(api_key.clone()))?).await;
+
+  let created = ApiKeyCreated { key, info: api_key.into() };
+  Ok(tide::Response::builder(201).body(tide::Body::from_json(&created)?).build())
+}
+
+pub(crate) async fn delete_api_key(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let user = request_user(&req)?;
+  let id = req.param("id").unwrap_or_default();
+  let key = match get_record::<ApiKey>(&state.db, "api_keys", id).await? {
+    Some(k) if k.user == user || is_admin(&req) => k,
+    _ => return Ok(tide::Response::new(404)),
+  };
+
+  delete_record(&state.db, "api_keys", key.id.clone()).await?;
+  // the cache only knows the owner, so all of their tokens are checked again
+  state.auth.invalidate_user(&key.user).await;
+  audit::record(&req, "key.delete", &key.id, serde_json::to_value(ApiKeyInfo::from(key.clone()))?, serde_json::Value::Null).await;
+  Ok(tide::Response::new(200))
+}
+
+// None for unknown or expired keys, the owner losing permissions also takes them from the key
+pub(crate) async fn identify(state: &AppState, key: &str) -> tide::Result<Option<Identity>> {
+  if !key.starts_with(KEY_PREFIX) {
+    return Ok(None);
+  }
+  let now = chrono::Utc::now().timestamp();
+  let api_key = match get_collection_records::<ApiKey>(&state.db, "api_keys", Some(Filter::eq("hash", key_hash(key)))).await?.pop() {
+    Some(k) if k.expires > now => k,
+    _ => return Ok(None),
+  };
+  let owner = match get_record::<Owner>(&state.db, "users", &api_key.user).await? {
+    Some(o) => o,
+    None => return Ok(None),
+  };
+
+  // only runs when the key is not cached, which keeps this to one write per cache ttl
+  let db = state.db.clone();
+  let used = ApiKeyUsed { id: api_key.id.clone(), last_used: now };
+  async_std::task::spawn(async move {
+    if let Err(e) = modify_record(&db, "api_keys", used).await {
+      tide::log::warn!("Failed to record the use of an api key: {}", e);
+    }
+  });
+
+  // a key never grants more than its owner still has, and an admin owner has everything
+  let owner_permissions = Permissions::from_i32(owner.permissions);
+  let mut permissions = Permissions::from_i32(api_key.permissions);
+  if !owner_permissions.contains(Permissions::ADMIN) {
+    permissions &= owner_permissions;
+  }
+  let mut identity = Identity::new(api_key.user, permissions, api_key.expires);
+  identity.api_key = Some(api_key.id);
+  identity.scope = api_key.scope;
+  Ok(Some(identity))
+}
+
+fn key_hash(key: &str) -> String {
+  hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::test_support::TestApp;
+
+  #[async_std::test]
+  async fn accepts_api_keys() {
+    let app = TestApp::new();
+    let res = app.post("bob", "keys").body(json!({ "name": "ci", "permissions": 9, "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 400);
+    let mut res = app.post("root", "keys").body(json!({ "name": "deploy", "permissions": 10, "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(created["permissions"], 10);
+    let key = format!("ApiKey {}", created["key"].as_str().unwrap());
+    let res = app.client.get("cloud/usage").header("Authorization", key.as_str()).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.get("users").header("Authorization", key.as_str()).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.get("iframe/portainer").header("Authorization", key.as_str()).await.unwrap();
+    assert_eq!(res.status(), 403);
+    let mut res = app.post("bob", "keys").body(json!({ "name": "ci", "permissions": 8, "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    let key = format!("ApiKey {}", created["key"].as_str().unwrap());
+
+    let res = app.client.post("cloud/files/test/ci.txt").header("Authorization", key.as_str()).body("built").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.post("keys").header("Authorization", key.as_str()).body(json!({ "name": "more", "permissions": 8, "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 403);
+
+    let mut res = app.get("bob", "keys").await.unwrap();
+    let keys: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(keys[0]["name"], "ci");
+    assert!(keys[0].get("hash").is_none());
+
+    let res = app.delete("bob", format!("keys/{}", created["id"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.get("cloud/dirs/test").header("Authorization", key.as_str()).await.unwrap();
+    assert_eq!(res.status(), 401);
+  }
+
+  #[async_std::test]
+  async fn limits_scoped_keys_to_their_subtree() {
+    let app = TestApp::new();
+    let scope = json!({ "name": "backup", "permissions": 8, "expires": 4102444800i64, "scope": { "path": "test/backups", "write": true } });
+    let mut res = app.post("bob", "keys").body(scope).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    let key = format!("ApiKey {}", created["key"].as_str().unwrap());
+
+    let res = app.client.post("cloud/files/test/backups/db.sql").header("Authorization", key.as_str()).body("dump").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.post("cloud/files/test/other.txt").header("Authorization", key.as_str()).body("dump").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let res = app.client.post("cloud/files/test/other.txt").header("Authorization", key.as_str()).header("Scope-Path", "test").body("dump").await.unwrap();
+    assert_eq!(res.status(), 403);
+
+    let res = app.post("bob", "cloud/files/test/other.txt").body("mine").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.client.get("cloud/dirs/test").header("Authorization", key.as_str()).await.unwrap();
+    let listing: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(listing["files"].as_array().unwrap().len(), 1);
+    assert_eq!(listing["files"][0]["name"], "backups");
+    assert_eq!(listing["files"][0]["write"], true);
+  }
+}
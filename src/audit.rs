@@ -0,0 +1,177 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tide::Request;
+
+use crate::{db::{create_record, get_collection_page, get_collection_records, Filter, PageQuery}, permissions::request_user, rate_limit::client_ip, state::AppState};
+
+const ID_CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+// only ever created, there is no route that changes or removes entries
+#[derive(Serialize, Deserialize)]
+struct AuditEntry {
+  id: String,
+  actor: String,
+  action: String,
+  target: String,
+  #[serde(default)]
+  before: Value,
+  #[serde(default)]
+  after: Value,
+  ip: String,
+  timestamp: i64,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+  user: Option<String>,
+  action: Option<String>,
+  from: Option<i64>,
+  to: Option<i64>,
+}
+
+// failures are logged instead of returned, the action itself already happened
+pub(crate) async fn record(req: &Request<AppState>, action: &str, target: &str, before: Value, after: Value) {
+  let state = req.state();
+  let now = chrono::Utc::now();
+  let entry = AuditEntry {
+    id: entry_id(now.timestamp_millis()),
+    actor: request_user(req).unwrap_or_default().to_string(),
+    action: action.to_string(),
+    target: target.to_string(),
+    before,
+    after,
+    ip: client_ip(req, state.config.rate_limit.trust_proxy),
+    timestamp: now.timestamp(),
+  };
+  if let Err(e) = create_record(&state.db, "audit", &entry).await {
+    tide::log::error!("Failed to write the audit log, {} {} by {}: {}", entry.action, entry.target, entry.actor, e);
+  }
+}
+
+pub(crate) async fn get_audit(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let page: PageQuery = req.query()?;
+  let AuditQuery { user, action, from, to } = req.query()?;
+  let filter = [
+    user.map(|u| Filter::eq("actor", u)),
+    action.map(|a| Filter::eq("action", a)),
+    from.map(|f| Filter::ge("timestamp", f)),
+    to.map(|t| Filter::le("timestamp", t)),
+  ].into_iter().flatten().reduce(Filter::and);
+
+  let body = match page.page() {
+    Some((page, per_page)) => tide::Body::from_json(&get_collection_page::<AuditEntry>(&state.db, "audit", filter, page, per_page).await?)?,
+    None => tide::Body::from_json(&get_collection_records::<AuditEntry>(&state.db, "audit", filter).await?)?,
+  };
+  Ok(tide::Response::builder(200).body(body).build())
+}
+
+// records are sorted by id, so ids start with the time and pages come out oldest first
+fn entry_id(millis: i64) -> String {
+  let mut time = String::new();
+  let mut rest = millis.max(0) as u64;
+  for _ in 0..9 {
+    time.insert(0, ID_CHARS[(rest % 36) as usize] as char);
+    rest /= 36;
+  }
+  let mut rng = rand::thread_rng();
+  let suffix: String = (0..6).map(|_| ID_CHARS[rng.gen_range(0..ID_CHARS.len())] as char).collect();
+  format!("{}{}", time, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::{config::Config, test_support::{Records, TestApp}};
+
+  #[test]
+  fn sorts_ids_by_time() {
+    let earlier = entry_id(1_700_000_000_000);
+    let later = entry_id(1_700_000_000_001);
+    assert_eq!(earlier.len(), 15);
+    assert!(earlier.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+    assert!(earlier < later);
+    assert!(entry_id(999) < entry_id(1_000));
+  }
+
+  #[async_std::test]
+  async fn audits_file_changes() {
+    let app = TestApp::new();
+    let res = app.post("bob", "cloud/files/test/report.txt").body("draft").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.patch("bob", "cloud/files/test/report.txt").body(json!({ "name": "final.txt" })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/files/test/final.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = app.get("bob", "audit").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let mut res = app.get("root", "audit?user=bob").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let entries: serde_json::Value = res.body_json().await.unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["action"], "file.rename");
+    assert_eq!(entries[0]["actor"], "bob");
+    assert_eq!(entries[0]["after"]["path"], "test/final.txt");
+    assert_eq!(entries[1]["action"], "file.delete");
+    assert_eq!(entries[1]["target"], "test/final.txt");
+  }
+
+  #[async_std::test]
+  async fn audits_every_change_of_a_record_under_its_id() {
+    let records = Records::default();
+    let app = TestApp::on(Config::default(), records.clone());
+    let id_of = |collection: &str| records.lock().unwrap().iter().rev().find(|(c, _)| c == collection).map(|(_, r)| r["id"].as_str().unwrap().to_string()).unwrap();
+    let changes = [
+      ("cloud/access", "access", "cloud", json!({ "user": "bob", "dir": "docs", "write": false }), json!({ "user": "bob", "dir": "docs", "write": true })),
+      ("users", "user", "users", json!({ "name": "Eve", "username": "eve", "password": "p", "passwordConfirm": "p", "verified": true }), json!({ "name": "Eve", "username": "eve2" })),
+      ("cloud/quotas", "quota", "cloud_quotas", json!({ "user": "bob", "dir": "", "limit": 10 }), json!({ "user": "bob", "dir": "", "limit": 20 })),
+    ];
+    for (route, kind, collection, create, mut update) in changes {
+      let res = app.post("root", route).body(create).await.unwrap();
+      assert_eq!(res.status(), 200);
+      let id = id_of(collection);
+      update["id"] = json!(id);
+      let res = app.patch("root", route).body(update).await.unwrap();
+      assert_eq!(res.status(), 200);
+      let res = app.delete("root", route).body(json!({ "id": id })).await.unwrap();
+      assert_eq!(res.status(), 200);
+
+      for action in ["create", "update", "delete"] {
+        let mut res = app.get("root", format!("audit?action={}.{}", kind, action)).await.unwrap();
+        let entries: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(entries[0]["target"], id, "{}.{}", kind, action);
+      }
+    }
+  }
+
+  #[async_std::test]
+  async fn audits_restores() {
+    let app = TestApp::new();
+    for content in ["v1", "v2"] {
+      let res = app.post("bob", "cloud/files/test/a.txt").body(content).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    let mut res = app.get("bob", "cloud/versions/test/a.txt").await.unwrap();
+    let versions: serde_json::Value = res.body_json().await.unwrap();
+    let old = versions.as_array().unwrap().iter().find(|v| v["current"] == false).unwrap();
+    let res = app.post("bob", "cloud/versions/test/a.txt").body(json!({ "id": old["id"] })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/files/test/a.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.get("bob", "cloud/trash").await.unwrap();
+    let items: serde_json::Value = res.body_json().await.unwrap();
+    let res = app.post("bob", "cloud/trash").body(json!({ "id": items[0]["id"] })).await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let mut res = app.get("root", "audit?user=bob").await.unwrap();
+    let entries: serde_json::Value = res.body_json().await.unwrap();
+    let actions: Vec<_> = entries.as_array().unwrap().iter().map(|e| (e["action"].as_str().unwrap(), e["target"].as_str().unwrap())).collect();
+    assert_eq!(actions, [("version.restore", "test/a.txt"), ("file.delete", "test/a.txt"), ("trash.restore", "test/a.txt")]);
+    assert_eq!(entries[0]["after"]["version"], old["id"]);
+  }
+}
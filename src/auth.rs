@@ -1,10 +1,41 @@
-use serde::Deserialize;
-use surf::{http::headers::AUTHORIZATION, Client, Error};
+use std::{collections::HashMap, time::Duration};
+
+use async_std::sync::RwLock;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use surf::{http::headers::AUTHORIZATION, Client};
 use async_trait::async_trait;
-use tide::{Middleware, Next, Request, Response};
+use tide::{http::Method, Middleware, Next, Request, Response};
+
+use crate::{api_keys::{self, Scope}, config::Config, error::ApiError, oidc, permissions::Permissions, state::AppState};
+
+const MAX_CACHED_TOKENS: usize = 10_000;
+const USER_TOKEN_TYPES: [&str; 2] = ["authRecord", "auth"];
+const API_KEY_SCHEME: &str = "ApiKey ";
+const BEARER_SCHEME: &str = "Bearer ";
+// direct links carry their own token in the path
+const PUBLIC_ROUTES: [&str; 2] = ["/cloud/direct/", "/images/apod/direct/"];
 
 pub(crate) struct TokenAuth {}
 
+// tokens that were validated recently, keyed by their hash so the cache holds no credentials
+pub(crate) struct AuthCache {
+  ttl: Duration,
+  entries: RwLock<HashMap<String, Identity>>,
+}
+
+// stored in the request extensions for the handlers
+#[derive(Clone, Debug)]
+pub(crate) struct Identity {
+  pub(crate) user: String,
+  pub(crate) permissions: Permissions,
+  // set when the request was made with an api key instead of a user token
+  pub(crate) api_key: Option<String>,
+  pub(crate) scope: Option<Scope>,
+  expires: i64,
+}
+
 #[derive(Deserialize)]
 struct ResponseData {
   record: Record
@@ -16,43 +47,185 @@ struct Record {
   id: String,
 }
 
-async fn validate_token(token: &str) -> Result<Record, Error> {
-  let client = Client::new();
-  let req = client.post(format!("{}/api/collections/users/auth-refresh", *crate::PB_URL));
-  let req = req.header(AUTHORIZATION, token);
-  match req.send().await {
-      Ok(mut res) => {
-        let ResponseData { record } = match res.body_json().await {
-          Ok(user) => user,
-          Err(err) => return Err(err),
-        };
-        Ok(record)
+#[derive(Deserialize)]
+struct Claims {
+  id: String,
+  #[serde(rename = "type")]
+  kind: String,
+  exp: i64,
+}
+
+impl Identity {
+  pub(crate) fn new(user: String, permissions: Permissions, expires: i64) -> Self {
+    Identity { user, permissions, api_key: None, scope: None, expires }
+  }
+}
+
+impl AuthCache {
+  pub(crate) fn new(config: &Config) -> Self {
+    AuthCache { ttl: Duration::from_secs(config.auth.cache_ttl), entries: RwLock::new(HashMap::new()) }
+  }
+
+  async fn get(&self, token: &str) -> Option<Identity> {
+    let identity = self.entries.read().await.get(&token_hash(token)).cloned()?;
+    (identity.expires > chrono::Utc::now().timestamp()).then_some(identity)
+  }
+
+  // never trusted past the expiry of the token itself
+  async fn insert(&self, token: &str, mut identity: Identity) {
+    if self.ttl.is_zero() {
+      return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    identity.expires = identity.expires.min(now + self.ttl.as_secs() as i64);
+    let mut entries = self.entries.write().await;
+    if entries.len() >= MAX_CACHED_TOKENS {
+      entries.retain(|_, i| i.expires > now);
+      if entries.len() >= MAX_CACHED_TOKENS {
+        entries.clear();
       }
-      Err(err) => Err(err),
+    }
+    entries.insert(token_hash(token), identity);
+  }
+
+  // called when a user's permissions change, their next request is validated again
+  pub(crate) async fn invalidate_user(&self, user: &str) {
+    self.entries.write().await.retain(|_, i| i.user != user);
   }
 }
 
-#[async_trait]
-impl Middleware<()> for TokenAuth {
-    async fn handle(&self, mut req: Request<()>, next: Next<'_, ()>) -> tide::Result {
-        if req.url().path().starts_with("/cloud/direct/") && req.method() == tide::http::Method::Get {
-            return Ok(next.run(req).await);
-        }
+async fn validate_token(pb: &Client, token: &str) -> Result<Option<Record>, ApiError> {
+  let req = pb.post("api/collections/users/auth-refresh");
+  let req = req.header(AUTHORIZATION, token);
+  let mut res = req.send().await.map_err(|e| ApiError::Upstream("pocketbase", e.to_string()))?;
+  if res.status().is_server_error() {
+    return Err(ApiError::Upstream("pocketbase", format!("token check returned {}", res.status())));
+  }
+  if !res.status().is_success() {
+    return Ok(None);
+  }
+  match res.body_json::<ResponseData>().await {
+    Ok(ResponseData { record }) => Ok(Some(record)),
+    Err(_) => Ok(None),
+  }
+}
+
+// None when the token is not valid, errors only when pocketbase can't be asked
+async fn identify(state: &AppState, token: &str) -> tide::Result<Option<Identity>> {
+  if let Some(key) = token.strip_prefix(API_KEY_SCHEME) {
+    return api_keys::identify(state, key.trim()).await;
+  }
+  let token = token.strip_prefix(BEARER_SCHEME).unwrap_or(token);
+  if state.oidc.handles(token) {
+    return oidc::identify(state, token).await;
+  }
+
+  // expired or malformed tokens would be rejected by pocketbase anyway, so they are turned away here
+  let claims = match decode_part::<Claims>(token, 1) {
+    Some(c) if USER_TOKEN_TYPES.contains(&c.kind.as_str()) && c.exp > chrono::Utc::now().timestamp() => c,
+    _ => return Ok(None),
+  };
+
+  // the signature can't be checked here, pocketbase signs with a key per user that it never hands out,
+  // so only pocketbase can accept a token and the cache keeps that to once per ttl
+  let record = validate_token(&state.http.pocketbase, token).await?.filter(|r| r.id == claims.id);
+  Ok(record.map(|r| Identity::new(r.id, Permissions::from_i32(r.permissions), claims.exp)))
+}
 
-        if req.url().path().starts_with("/images/apod/direct/") && req.method() == tide::http::Method::Get {
+pub(crate) fn decode_part<T: DeserializeOwned>(token: &str, part: usize) -> Option<T> {
+  let data = URL_SAFE_NO_PAD.decode(token.split('.').nth(part)?.trim_end_matches('=')).ok()?;
+  serde_json::from_slice(&data).ok()
+}
+
+pub(crate) fn is_public(method: &Method, path: &str) -> bool {
+  *method == Method::Get && PUBLIC_ROUTES.iter().any(|p| path.starts_with(p))
+}
+
+fn token_hash(token: &str) -> String {
+  hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[async_trait]
+impl Middleware<AppState> for TokenAuth {
+    async fn handle(&self, mut req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+        if is_public(&req.method(), req.url().path()) {
             return Ok(next.run(req).await);
         }
 
         let token = match req.header("Authorization") {
-            Some(token) => token,
+            Some(token) => token.as_str().to_string(),
             None => return Ok(Response::new(401)),
         };
-        let record = match validate_token(token.as_str()).await {
-            Ok(record) => record,
-            Err(_) => return Ok(Response::new(401)),
+        let state = req.state().clone();
+        let identity = match state.auth.get(&token).await {
+            Some(identity) => identity,
+            None => match identify(&state, &token).await? {
+                Some(identity) => {
+                    state.auth.insert(&token, identity.clone()).await;
+                    identity
+                },
+                None => return Ok(Response::new(401)),
+            },
         };
-        req.insert_header("Permissions", record.permissions.to_string());
-        req.insert_header("User", record.id);
+        req.set_ext(identity);
         Ok(next.run(req).await)
     }
 }
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::test_support::{Records, TestApp};
+
+  #[test]
+  fn decodes_claims() {
+    let claims = URL_SAFE_NO_PAD.encode(json!({ "id": "u1", "type": "authRecord", "exp": 4102444800i64 }).to_string());
+    let claims = decode_part::<Claims>(&format!("e30.{}.sig", claims), 1).unwrap();
+    assert_eq!((claims.id.as_str(), claims.kind.as_str(), claims.exp), ("u1", "authRecord", 4102444800));
+    assert!(decode_part::<Claims>("not a token", 1).is_none());
+  }
+
+  #[async_std::test]
+  async fn caches_until_invalidated() {
+    let cache = AuthCache::new(&Config::default());
+    let now = chrono::Utc::now().timestamp();
+    cache.insert("a", Identity::new("u1".to_string(), Permissions::CLOUD, now + 3600)).await;
+    cache.insert("b", Identity::new("u2".to_string(), Permissions::CLOUD, now + 3600)).await;
+    cache.insert("c", Identity::new("u2".to_string(), Permissions::CLOUD, now - 1)).await;
+    assert_eq!(cache.get("a").await.unwrap().user, "u1");
+    assert!(cache.get("c").await.is_none());
+
+    cache.invalidate_user("u1").await;
+    assert!(cache.get("a").await.is_none());
+    assert!(cache.get("b").await.is_some());
+  }
+
+  #[async_std::test]
+  async fn rejects_missing_tokens() {
+    let app = TestApp::new();
+    let res = app.client.get("cloud/dirs/test").await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = app.get("mallory", "cloud/dirs/test").await.unwrap();
+    assert_eq!(res.status(), 401);
+  }
+
+  #[async_std::test]
+  async fn asks_pocketbase_once_per_token() {
+    let records = Records::default();
+    let app = TestApp::on(Config::default(), records.clone());
+    let refreshes = || records.lock().unwrap().iter().filter(|(c, _)| c == "auth-refresh").count();
+    for _ in 0..3 {
+      let res = app.get("bob", "cloud/usage").await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    assert_eq!(refreshes(), 1);
+
+    // expired tokens never reach pocketbase
+    let claims = json!({ "id": "bob", "type": "authRecord", "exp": 1 }).to_string();
+    let res = app.client.get("cloud/usage").header("Authorization", format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(claims))).await.unwrap();
+    assert_eq!(res.status(), 401);
+    assert_eq!(refreshes(), 1);
+  }
+}
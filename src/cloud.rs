@@ -1,360 +1,581 @@
-use std::{fs::File, io::{Cursor, Error, Read, Write}};
+use std::{fs::File, io::{Error, Write}};
 
-use async_std::io::ReadExt;
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use percent_encoding::percent_decode_str;
+use async_std::{io::{Read, ReadExt}, task};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tide::Request;
 use zip::ZipWriter;
 
-use crate::{db::{create_record, delete_record, get_collection_records, modify_record, ModifyRecord}, permissions::{has_permissions, is_admin, Permissions}};
+use crate::{audit, cloud_path::CloudPath, events::CloudEvent, gzip::{compress_to_file, decompress_file, decompress_range, read_index}, range::{unix_secs, Plan, Validators}, storage::{join, Metadata, Storage}, quota::{release_quota, reserve_quota}, trash::move_to_trash, versions::{archive_current, move_versions, prune, record_current, unarchive}, db::{create_record, delete_record, get_collection_page, get_collection_records, get_record, modify_record, new_record_id, Filter, ModifyRecord, PageQuery}, error::ApiError, permissions::{is_admin, request_identity, request_user}, state::AppState};
 
-pub(crate) async fn get_access(req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::CloudManage as i32) {
-      return Ok(tide::Response::new(403));
-    }
+// reserved for data kept next to the files like old versions, never reachable through the cloud routes
+pub(crate) const SYSTEM_DIR: &str = ".cloud";
 
-  let access = get_collection_records::<Access>("cloud", None).await?;
-  let res = tide::Response::builder(200).body(tide::Body::from_json(&access)?);
-  Ok(res.build())
+pub(crate) async fn get_access(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let query: PageQuery = req.query()?;
+  let body = match query.page() {
+    Some((page, per_page)) => tide::Body::from_json(&get_collection_page::<Access>(&state.db, "cloud", None, page, per_page).await?)?,
+    None => tide::Body::from_json(&get_collection_records::<Access>(&state.db, "cloud", None).await?)?,
+  };
+  Ok(tide::Response::builder(200).body(body).build())
 }
 
-pub(crate) async fn create_access(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::CloudManage as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
-  let new_access: AccessCreate = req.body_json().await?;
-  create_record("cloud", new_access).await?;
+pub(crate) async fn create_access(mut req: Request<AppState>) -> tide::Result {
+  let mut new_access: AccessCreate = req.body_json().await?;
+  new_access.id = new_record_id();
+  let state = req.state();
+  let after = serde_json::to_value(&new_access)?;
+  let target = new_access.id.clone();
+  create_record(&state.db, "cloud", new_access).await?;
+  audit::record(&req, "access.create", &target, Value::Null, after).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn delete_access(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::CloudManage as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
+pub(crate) async fn delete_access(mut req: Request<AppState>) -> tide::Result {
   let delete_access: AccessDelete = req.body_json().await?;
-  delete_record("cloud", delete_access.id).await?;
+  let state = req.state();
+  let before = get_record::<Access>(&state.db, "cloud", &delete_access.id).await?;
+  delete_record(&state.db, "cloud", delete_access.id.clone()).await?;
+  audit::record(&req, "access.delete", &delete_access.id, serde_json::to_value(before)?, Value::Null).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn update_access(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::CloudManage as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
+pub(crate) async fn update_access(mut req: Request<AppState>) -> tide::Result {
   let modify_access: AccessUpdate = req.body_json().await?;
-  modify_record("cloud", modify_access).await?;
+  let state = req.state();
+  let before = get_record::<Access>(&state.db, "cloud", &modify_access.id).await?;
+  let after = serde_json::to_value(&modify_access)?;
+  let target = modify_access.id.clone();
+  modify_record(&state.db, "cloud", modify_access).await?;
+  audit::record(&req, "access.update", &target, serde_json::to_value(before)?, after).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn get_dir_files(req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Cloud as i32) {
-    return Ok(tide::Response::new(403));
-  }
-  
-  let dir = percent_decode_str(req.param("path").unwrap_or_default()).decode_utf8_lossy().to_string();
-  let files: Vec<CloudFileTemp> = match std::fs::read_dir(format!("{}/{}", *crate::CLOUD_DIR, dir)) {
-    Ok(f) => f.filter_map(|f| f.ok()).map(|f| CloudFileTemp{name: f.file_name().to_string_lossy().to_string(), dir: f.file_type().unwrap().is_dir()}).collect(),
+pub(crate) async fn get_dir_files(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let dir = match CloudPath::from_param(&req, "path") {
+    Ok(d) => d,
+    Err(e) => return Ok(e.response()),
+  };
+  let files: Vec<CloudFileTemp> = match state.storage.list(&dir).await {
+    Ok(f) => f.into_iter().map(|f| CloudFileTemp{name: f.name, dir: f.dir}).collect(),
     Err(_) => return Ok(tide::Response::new(410)),
   };
   
-  let final_files = check_files_access(&req, files, dir).await;
+  let ListQuery { sort, order, page, per_page } = req.query()?;
+  let mut final_files = check_files_access(&req, files, &dir).await?;
+
+  // sorting by name needs no metadata, so only the requested page has to be looked up
+  let by_name = sort == SortKey::Name;
+  if !by_name {
+    for file in &mut final_files {
+      add_metadata(state, &dir, file).await;
+    }
+  }
+
+  final_files.sort_by(|a, b| {
+    let ord = match sort {
+      SortKey::Name => a.name.cmp(&b.name),
+      SortKey::Size => a.size.or(a.children.map(|c| c as u64)).cmp(&b.size.or(b.children.map(|c| c as u64))),
+      SortKey::Modified => a.modified.cmp(&b.modified),
+      SortKey::Created => a.created.cmp(&b.created),
+      SortKey::Type => a.mime.cmp(&b.mime).then(a.name.cmp(&b.name)),
+    };
+    b.dir.cmp(&a.dir).then(if order == SortOrder::Desc { ord.reverse() } else { ord })
+  });
+
+  let total = final_files.len();
+  if let Some(per_page) = per_page.filter(|&p| p > 0) {
+    let page = page.unwrap_or(1).max(1);
+    final_files = final_files.into_iter().skip((page - 1) * per_page).take(per_page).collect();
+  }
 
-  Ok(tide::Response::builder(200).body(tide::Body::from_json(&CloudFiles{files: final_files})?).build())
+  if by_name {
+    for file in &mut final_files {
+      add_metadata(state, &dir, file).await;
+    }
+  }
+
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&CloudFiles{files: final_files, total})?).build())
 }
 
-pub(crate) async fn upload_file(mut req: Request<()>) -> tide::Result {
-  let (path, dir) = match check_permissions(&req, false, true).await {
+pub(crate) async fn upload_file(mut req: Request<AppState>) -> tide::Result {
+  let (path, _) = match check_permissions(&req, false, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  let mut file = req.take_body();
-  let mut data = Vec::new();
-  file.read_to_end(&mut data).await?;
-
-  let mut encoder = GzEncoder::new(Vec::new(), Compression::new(4));
-  encoder.write_all(&data).unwrap();
-  let comp = encoder.finish().unwrap();
-
-  async_std::fs::create_dir_all(format!("{}/{}", *crate::CLOUD_DIR, dir)).await?;
-  async_std::fs::write(format!("{}/{}", *crate::CLOUD_DIR, path), comp).await?;
+  let user = request_user(&req)?.to_string();
+  let reservation = format!("upload/{}", rand::random::<u128>());
+  if let Some(res) = reserve_quota(req.state(), &reservation, &user, &path, req.len().map(|l| l as u64)).await? {
+    return Ok(res);
+  }
 
+  let body = req.take_body();
+  let stored = store_file(req.state(), body, &path, &user).await;
+  release_quota(req.state(), &reservation).await;
+  stored?;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn download_file(req: Request<()>) -> tide::Result {
+pub(crate) async fn download_file(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, false, false).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  let data = match async_std::fs::read(format!("{}/{}", *crate::CLOUD_DIR, path)).await {
-    Ok(d) => d,
-    Err(_) => return Ok(tide::Response::new(410)),
+  let metadata = match state.storage.metadata(&path).await {
+    Ok(m) if !m.dir => m,
+    _ => return Ok(tide::Response::new(410)),
   };
 
-  let mut decoder = GzDecoder::new(&data[..]);
-  let mut decomp = Vec::new();
-  decoder.read_to_end(&mut decomp).unwrap();
-
-  Ok(tide::Response::builder(200).body(decomp).build())
+  serve_file(&req, &path, &metadata).await
 }
 
-pub(crate) async fn download_multiple(mut req: Request<()>) -> tide::Result {
+pub(crate) async fn download_multiple(mut req: Request<AppState>) -> tide::Result {
   let (path, _) = match check_permissions(&req, false, false).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
   let files: Vec<String> = req.body_json().await?;
-  let comp = pack_zip(&path, files)?;
+  let state = req.state();
+  let mut names = Vec::new();
+  for file in files {
+    match path.join(&file) {
+      Ok(p) => names.push(p[path.len()..].trim_start_matches('/').to_string()),
+      Err(e) => return Ok(e.response()),
+    }
+  }
+  let comp = pack_zip(state, &path, names).await?;
 
   Ok(tide::Response::builder(200).body(comp).header("Content-Type", "application/zip").header("Content-Disposition", "attachment; filename=files.zip").build())
 
 }
 
-pub(crate) async fn check_if_exists(req: Request<()>) -> tide::Result {
+pub(crate) async fn check_if_exists(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, false, false).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  let exists = async_std::fs::metadata(format!("{}/{}", *crate::CLOUD_DIR, path)).await.is_ok();
+  let exists = state.storage.metadata(&path).await.is_ok();
   Ok(tide::Response::builder(200).body(tide::Body::from_json(&exists)?).build())
 }
 
-pub(crate) async fn check_if_exists_multiple(mut req: Request<()>) -> tide::Result {
+pub(crate) async fn check_if_exists_multiple(mut req: Request<AppState>) -> tide::Result {
   let (_, dir) = match check_permissions(&req, true, false).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
   let files: Vec<String> = req.body_json().await?;
+  let state = req.state();
   let temp = files.iter().map(|f| CloudFileTemp{name: f.clone(), dir: false}).collect();
-  let cloud = check_files_access(&req, temp, dir.clone()).await;
+  let cloud = check_files_access(&req, temp, &dir).await?;
 
-  let parrent_dir = if dir.is_empty() {
-    crate::CLOUD_DIR.clone()
-  } else {
-    format!("{}/{}", *crate::CLOUD_DIR, dir)
-  };
   let mut exists = Vec::new();
   for file in cloud {
-    exists.push(async_std::fs::metadata(format!("{}/{}", parrent_dir, file.name)).await.is_ok());
+    exists.push(state.storage.metadata(&join(&dir, &file.name)).await.is_ok());
   }
   exists.retain(|&e| e);
 
   Ok(tide::Response::builder(200).body(tide::Body::from_json(&Exists{ count: exists.len() as i32 })?).build())
 }
 
-pub(crate) async fn create_dir(req: Request<()>) -> tide::Result {
+pub(crate) async fn create_dir(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, false, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  async_std::fs::create_dir_all(format!("{}/{}", *crate::CLOUD_DIR, path)).await?;
+  state.storage.create_dir(&path).await?;
+  state.events.publish(CloudEvent::created(&path, true)).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn delete_file(req: Request<()>) -> tide::Result {
+pub(crate) async fn delete_file(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, false, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  async_std::fs::remove_file(format!("{}/{}", *crate::CLOUD_DIR, path)).await?;
+  match state.storage.metadata(&path).await {
+    Ok(m) if !m.dir => (),
+    _ => return Ok(tide::Response::new(410)),
+  };
+
+  let user = request_user(&req)?;
+  move_to_trash(state, &path, false, user).await?;
+  state.events.publish(CloudEvent::deleted(&path, false)).await;
+  audit::record(&req, "file.delete", &path.to_string(), Value::Null, Value::Null).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn delete_dir(req: Request<()>) -> tide::Result {
+pub(crate) async fn delete_dir(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, true, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  async_std::fs::remove_dir_all(format!("{}/{}", *crate::CLOUD_DIR, path)).await?;
+  match state.storage.metadata(&path).await {
+    Ok(m) if m.dir && !path.is_root() => (),
+    _ => return Ok(tide::Response::new(410)),
+  };
+
+  let user = request_user(&req)?;
+  move_to_trash(state, &path, true, user).await?;
+  state.events.publish(CloudEvent::deleted(&path, true)).await;
+  audit::record(&req, "dir.delete", &path.to_string(), Value::Null, Value::Null).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn rename_file(req: Request<()>) -> tide::Result {
-  let (path, _) = match check_permissions(&req, false, true).await {
-    Ok(p) => p,
-    Err(r) => return Ok(r),
-  };
+pub(crate) async fn rename_file(req: Request<AppState>) -> tide::Result {
+  rename(req, false).await
+}
 
-  let new_name = req.param("new_name").unwrap_or_default().to_string();
-  let new_path = format!("{}/{}", path.split('/').take(path.split('/').count() - 1).collect::<Vec<&str>>().join("/"), new_name);
-  async_std::fs::rename(format!("{}/{}", *crate::CLOUD_DIR, path), format!("{}/{}", *crate::CLOUD_DIR, new_path)).await?;
-  Ok(tide::Response::new(200))
+pub(crate) async fn rename_dir(req: Request<AppState>) -> tide::Result {
+  rename(req, true).await
 }
 
-pub(crate) async fn rename_dir(req: Request<()>) -> tide::Result {
-  let (path, _) = match check_permissions(&req, true, true).await {
+async fn rename(mut req: Request<AppState>, is_dir: bool) -> tide::Result {
+  let (path, _) = match check_permissions(&req, is_dir, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  let new_name = req.param("new_name").unwrap_or_default().to_string();
-  let new_path = format!("{}/{}", path.split('/').take(path.split('/').count() - 1).collect::<Vec<&str>>().join("/"), new_name);
-  async_std::fs::rename(format!("{}/{}", *crate::CLOUD_DIR, path), format!("{}/{}", *crate::CLOUD_DIR, new_path)).await?;
+  let Rename { name } = req.body_json().await?;
+  let state = req.state();
+  let new_path = match path.with_name(&name) {
+    Ok(p) => p,
+    Err(e) => return Ok(e.response()),
+  };
+  if let Err(r) = check_path_permissions(&req, new_path.clone(), is_dir, true).await {
+    return Ok(r);
+  }
+
+  match state.storage.metadata(&path).await {
+    Ok(m) if m.dir == is_dir => (),
+    _ => return Ok(tide::Response::new(410)),
+  };
+  if state.storage.metadata(&new_path).await.is_ok() {
+    return Ok(tide::Response::new(409));
+  }
+
+  state.storage.rename(&path, &new_path).await?;
+  move_versions(state, &path, &new_path).await?;
+  state.events.publish(CloudEvent::renamed(&path, &new_path, is_dir)).await;
+  let action = if is_dir { "dir.rename" } else { "file.rename" };
+  audit::record(&req, action, &path.to_string(), json!({ "path": path.to_string() }), json!({ "path": new_path.to_string() })).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn create_direct_link(req: Request<()>) -> tide::Result {
+pub(crate) async fn create_direct_link(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
   let (path, _) = match check_permissions(&req, false, true).await {
     Ok(p) => p,
     Err(r) => return Ok(r),
   };
 
-  let direct_link = get_collection_records::<DirectLink>("direct_cloud", Some(&format!("path='{}'", path))).await?;
+  let direct_link = get_collection_records::<DirectLink>(&state.db, "direct_cloud", Some(Filter::eq("path", path.to_string()))).await?;
   if !direct_link.is_empty() {
-    let link = format!("{}/{}", *crate::CLOUD_URL, direct_link[0].uuid);
+    let link = format!("{}/{}", state.config.cloud.url, direct_link[0].uuid);
     return Ok(tide::Response::builder(200).body(tide::Body::from_json(&link)?).build());
   }
   
   let random = rand::random::<u128>();
-  let link = format!("{}/{}", *crate::CLOUD_URL, random);
-  let direct_link = DirectLink{uuid: random.to_string(), path};
+  let link = format!("{}/{}", state.config.cloud.url, random);
+  let direct_link = DirectLink{uuid: random.to_string(), path: path.to_string()};
 
-  create_record("direct_cloud", direct_link).await?;
+  create_record(&state.db, "direct_cloud", direct_link).await?;
+  audit::record(&req, "link.create", &path.to_string(), Value::Null, Value::Null).await;
 
   Ok(tide::Response::builder(200).body(tide::Body::from_json(&link)?).build())
 }
 
-pub(crate) async fn get_direct_link(req: Request<()>) -> tide::Result {
-  let uuid = req.param("uuid").unwrap_or_default().parse::<u128>().unwrap();
-  let direct_link = get_collection_records::<DirectLink>("direct_cloud", Some(&format!("uuid=\"{}\"", uuid))).await?;
+pub(crate) async fn get_direct_link(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let uuid = req.param("uuid").unwrap_or_default().parse::<u128>().map_err(|_| ApiError::NotFound)?;
+  let direct_link = get_collection_records::<DirectLink>(&state.db, "direct_cloud", Some(Filter::eq("uuid", uuid.to_string()))).await?;
   if direct_link.is_empty() {
     return Ok(tide::Response::new(404));
   }
 
-  let path = format!("{}/{}", *crate::CLOUD_DIR, direct_link[0].path);
-  let mut file_name = path.clone().split('/').last().unwrap().to_string();
-  let mut file = File::open(&path)?;
-  let decomp = if file.metadata()?.is_dir() {
-    let dir = std::fs::read_dir(path)?;
-    let files: Vec<String> = dir.filter_map(|f| f.ok()).map(|f| f.file_name().to_string_lossy().to_string()).collect();
+  let path = match CloudPath::parse(&direct_link[0].path) {
+    Ok(p) => p,
+    Err(_) => return Ok(tide::Response::new(404)),
+  };
+  let mut file_name = path.name().to_string();
+  let metadata = state.storage.metadata(&path).await?;
+  let mut res = if metadata.dir {
+    let files: Vec<String> = state.storage.list(&path).await?.into_iter().map(|f| f.name).collect();
     file_name = format!("{}.zip", file_name);
-    pack_zip(&direct_link[0].path, files)?
+    tide::Response::builder(200).body(pack_zip(state, &path, files).await?).build()
   } else {
-    let mut data = Vec::new();
-    file.read_to_end(&mut data)?;
-
-    let mut decoder = GzDecoder::new(&data[..]);
-    let mut decomp = Vec::new();
-    decoder.read_to_end(&mut decomp).unwrap();
-    decomp
+    serve_file(&req, &path, &metadata).await?
   };
 
-  Ok(tide::Response::builder(200).body(decomp).header("Content-Disposition", format!("attachment; filename={}", file_name)).build())
+  res.insert_header("Content-Disposition", format!("attachment; filename={}", file_name));
+  Ok(res)
 }
 
-async fn check_permissions(req: &Request<()>, is_dir: bool, write: bool) -> Result<(String, String), tide::Response> {
-  if !has_permissions(req, Permissions::Cloud as i32) {
-    return Err(tide::Response::new(403));
-  }
+pub(crate) async fn check_permissions(req: &Request<AppState>, is_dir: bool, write: bool) -> Result<(CloudPath, CloudPath), tide::Response> {
+  let path = CloudPath::from_param(req, "path").map_err(|e| e.response())?;
+  check_path_permissions(req, path, is_dir, write).await
+}
 
-  let path = percent_decode_str(req.param("path").unwrap_or_default()).decode_utf8_lossy().to_string();
+pub(crate) async fn check_path_permissions(req: &Request<AppState>, path: CloudPath, is_dir: bool, write: bool) -> Result<(CloudPath, CloudPath), tide::Response> {
   let dir = if is_dir {
     path.clone()
   } else {
-    path.split('/').take(path.split('/').count() - 1).collect::<Vec<&str>>().join("/")
+    path.parent()
   };
 
-  if !check_access(req, &dir, write).await && !is_admin(req) {
-    return Err(tide::Response::new(403));
+  match token_scope(req) {
+    Ok(Some((scope, scope_write))) if !path.is_within(&scope) || write && !scope_write => return Err(tide::Response::new(403)),
+    Err(e) => return Err(e.response()),
+    _ => (),
+  }
+
+  if !is_admin(req) {
+    match check_access(req, &dir, write).await {
+      Ok(true) => (),
+      Ok(false) => return Err(tide::Response::new(403)),
+      Err(e) => return Err(e.into()),
+    }
   }
 
   Ok((path, dir))
 }
 
-async fn check_access(req: &Request<()>, dir: &str, write: bool) -> bool {
-  let user = req.header("User").unwrap().as_str();
-  let access = get_collection_records::<Access>("cloud", Some(&format!("user='{}'", user))).await.unwrap();
-  access.iter()
-    .filter(|&a| !write || a.write == write)
-    .filter(|a| dir.starts_with(&format!("{}/", a.dir)) || dir == a.dir)
-    .reduce(|a, x| if a.dir.len() > x.dir.len() {a} else {x})
-    .is_some()
+async fn check_access(req: &Request<AppState>, dir: &CloudPath, write: bool) -> tide::Result<bool> {
+  Ok(get_access_paths(req).await?.iter()
+    .filter(|&a| !write || a.1 == write)
+    .filter(|a| dir.is_within(&a.0))
+    .reduce(|a, x| if a.0.len() > x.0.len() {a} else {x})
+    .is_some())
 }
 
-async fn check_files_access(req: &Request<()>, files: Vec<CloudFileTemp>, dir: String) -> Vec<CloudFile> {
-  let user = req.header("User").unwrap().as_str();
-  let access = get_collection_records::<Access>("cloud", Some(&format!("user='{}'", user))).await.unwrap();
+// entries that are no valid cloud paths, like the system directory, are never listed
+async fn check_files_access(req: &Request<AppState>, files: Vec<CloudFileTemp>, dir: &CloudPath) -> tide::Result<Vec<CloudFile>> {
+  let access = get_access_paths(req).await?;
+  let scope = token_scope(req)?;
   let is_admin = is_admin(req);
   let mut final_files = Vec::new();
   for file in files {
-    let file_name_format = if dir.is_empty() {
-      file.name.clone()
-    } else {
-      format!("{}/{}", dir, file.name)
+    let file_path = match dir.join(&file.name) {
+      Ok(p) if p.name() == file.name => p,
+      _ => continue,
     };
-    let parent_access = access.iter()
-      .filter(|a| file_name_format.starts_with(&format!("{}/", a.dir)) || file_name_format == a.dir)
-      .reduce(|a, x| if a.dir.len() > x.dir.len() {a} else {x});
-    
-    if is_admin {
-      final_files.push(CloudFile{name: file.name, dir: file.dir, write: true});
-    } else if parent_access.is_some(){
-      final_files.push(CloudFile{name: file.name, dir: file.dir, write: parent_access.unwrap().write});
-    } else {
-      let child_access = access.iter()
-        .filter(|&a| a.dir.starts_with(&format!("{}/", file_name_format)) || a.dir == file_name_format)
-        .reduce(|a, x| if a.dir.len() > x.dir.len() {a} else {x});
-      if child_access.is_some() {
-        final_files.push(CloudFile{name: file.name, dir: file.dir, write: false});
-      }
+    if let Some(write) = path_access(&access, &scope, is_admin, &file_path) {
+      final_files.push(CloudFile{name: file.name, dir: file.dir, write, ..Default::default()});
     }
   }
-  final_files
+  Ok(final_files)
 }
 
-fn pack_zip(path: &str, files: Vec<String>) -> Result<Vec<u8>, Error> {
-  let path = format!("{}/{}", *crate::CLOUD_DIR, path);
-  let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+// None when the path is hidden from the user, otherwise whether they may write to it
+pub(crate) fn path_access(access: &[(CloudPath, bool)], scope: &Option<(CloudPath, bool)>, is_admin: bool, path: &CloudPath) -> Option<bool> {
+  let parent_access = access.iter()
+    .filter(|a| path.is_within(&a.0))
+    .reduce(|a, x| if a.0.len() > x.0.len() {a} else {x});
+
+  let write = if is_admin {
+    true
+  } else if let Some((_, write)) = parent_access {
+    *write
+  } else {
+    // directories leading to an accessible one are visible but read-only
+    access.iter().find(|a| a.0.is_within(path))?;
+    false
+  };
+
+  // a scoped token only sees its subtree and the directories leading to it
+  match scope {
+    Some((scope, scope_write)) if path.is_within(scope) => Some(write && *scope_write),
+    Some((scope, _)) if scope.is_within(path) => Some(false),
+    Some(_) => None,
+    None => Some(write),
+  }
+}
+
+// the subtree and write mode a scoped api key is limited to, None for unrestricted requests
+pub(crate) fn token_scope(req: &Request<AppState>) -> Result<Option<(CloudPath, bool)>, ApiError> {
+  let scope = match request_identity(req).and_then(|i| i.scope.as_ref()) {
+    Some(s) => s,
+    None => return Ok(None),
+  };
+  let path = CloudPath::parse(&scope.path).map_err(|_| ApiError::Forbidden)?;
+  Ok(Some((path, scope.write)))
+}
+
+pub(crate) async fn get_access_paths(req: &Request<AppState>) -> tide::Result<Vec<(CloudPath, bool)>> {
+  let state = req.state();
+  let user = request_user(req)?;
+  let access = get_collection_records::<Access>(&state.db, "cloud", Some(Filter::eq("user", user))).await?;
+  Ok(access.into_iter().filter_map(|a| CloudPath::parse(&a.dir).ok().map(|d| (d, a.write))).collect())
+}
+
+async fn add_metadata(state: &AppState, dir: &str, file: &mut CloudFile) {
+  let path = join(dir, &file.name);
+  let metadata = match state.storage.metadata(&path).await {
+    Ok(m) => m,
+    Err(_) => return,
+  };
+
+  file.modified = Some(unix_secs(metadata.modified));
+  file.created = metadata.created.map(unix_secs);
+  if file.dir {
+    file.children = state.storage.list(&path).await.ok().map(|c| c.len());
+  } else {
+    // files without an index would have to be decompressed, so their size stays unknown
+    file.size = read_index(&*state.storage, &path, metadata.size).await.ok().flatten().map(|i| i.size);
+    file.compressed_size = Some(metadata.size);
+    file.mime = Some(mime_guess::from_path(&file.name).first_or_octet_stream().to_string());
+  }
+}
+
+pub(crate) async fn serve_file(req: &Request<AppState>, path: &str, metadata: &Metadata) -> tide::Result {
+  let state = req.state();
+  let validators = Validators::new(metadata);
+  let index = read_index(&*state.storage, path, metadata.size).await?;
+  let size = index.as_ref().map(|i| i.size);
+
+  let mut res = match validators.evaluate(req, size) {
+    Plan::NotModified => tide::Response::new(304),
+    Plan::Unsatisfiable => tide::Response::builder(416).header("Content-Range", format!("bytes */{}", size.unwrap_or_default())).build(),
+    Plan::Partial(start, len) => {
+      // a partial plan is only made for files with a known size, which comes from the index
+      let index = index.as_ref().ok_or(ApiError::Internal)?;
+      let reader = decompress_range(&*state.storage, path, index, start, len).await?;
+      tide::Response::builder(206)
+        .body(tide::Body::from_reader(reader, Some(len as usize)))
+        .header("Content-Range", format!("bytes {}-{}/{}", start, start + len - 1, size.unwrap_or_default()))
+        .build()
+    },
+    Plan::Full => {
+      let reader = decompress_file(&*state.storage, path).await?;
+      tide::Response::builder(200).body(tide::Body::from_reader(reader, size.map(|s| s as usize))).build()
+    },
+  };
+
+  if size.is_some() {
+    res.insert_header("Accept-Ranges", "bytes");
+  }
+  validators.apply(&mut res);
+  Ok(res)
+}
+
+// the uncompressed size, files without an index have to be decompressed once to find it
+pub(crate) async fn file_size(state: &AppState, path: &str, metadata: &Metadata) -> Result<u64, Error> {
+  if let Some(index) = read_index(&*state.storage, path, metadata.size).await? {
+    return Ok(index.size);
+  }
+  let reader = decompress_file(&*state.storage, path).await?;
+  async_std::io::copy(reader, async_std::io::sink()).await
+}
+
+pub(crate) async fn store_file<R>(state: &AppState, reader: R, path: &str, user: &str) -> tide::Result<u64> where R: Read + Unpin {
+  async_std::fs::create_dir_all(&state.config.cloud.upload_dir).await?;
+  let tmp = format!("{}/{}.gz", state.config.cloud.upload_dir, rand::random::<u128>());
+  let size = match compress_to_file(reader, &tmp).await {
+    Ok(s) => s,
+    Err(e) => {
+      async_std::fs::remove_file(&tmp).await.ok();
+      return Err(e.into());
+    },
+  };
+
+  let archived = archive_current(state, path).await?;
+  if let Err(e) = state.storage.import(path, &tmp).await {
+    async_std::fs::remove_file(&tmp).await.ok();
+    if let Some(key) = archived {
+      unarchive(state, path, &key).await?;
+    }
+    return Err(e.into());
+  }
+
+  record_current(state, path, user, size).await?;
+  prune(state, path).await?;
+  if let Ok(path) = CloudPath::parse(path) {
+    let event = if archived.is_some() { CloudEvent::updated(&path) } else { CloudEvent::created(&path, false) };
+    state.events.publish(event).await;
+  }
+  Ok(size)
+}
+
+async fn pack_zip(state: &AppState, path: &str, files: Vec<String>) -> Result<tide::Body, Error> {
+  async_std::fs::create_dir_all(&state.config.cloud.upload_dir).await?;
+  let tmp = format!("{}/{}.zip", state.config.cloud.upload_dir, rand::random::<u128>());
+
+  let (storage, path, tmp_zip) = (state.storage.clone(), path.to_string(), tmp.clone());
+  let packed = task::spawn_blocking(move || write_zip(&*storage, &path, files, &tmp_zip)).await;
+  let file = match packed {
+    Ok(()) => async_std::fs::File::open(&tmp).await,
+    Err(e) => Err(e),
+  };
+  // the open handle keeps the data readable after the temporary file is unlinked
+  async_std::fs::remove_file(&tmp).await.ok();
+  let file = file?;
+
+  let len = file.metadata().await?.len() as usize;
+  Ok(tide::Body::from_reader(async_std::io::BufReader::new(file), Some(len)))
+}
+
+// runs on a blocking thread, the storage reads are driven with block_on
+fn write_zip(storage: &dyn Storage, path: &str, files: Vec<String>, tmp: &str) -> Result<(), Error> {
+  let mut zip = zip::ZipWriter::new(File::create(tmp)?);
   for file_name in files {
-    let mut file = File::open(format!("{}/{}", path, file_name))?;
-    if file.metadata()?.is_dir() {
-      get_files_from_folder(&path, &file_name, &mut zip)?;
+    if path.is_empty() && file_name == SYSTEM_DIR {
+      continue;
+    }
+    if task::block_on(storage.metadata(&join(path, &file_name)))?.dir {
+      get_files_from_folder(storage, path, &file_name, &mut zip)?;
     } else {
-      zip.start_file(file_name, Default::default())?;
-      std::io::copy(&mut file, &mut zip)?;
+      zip.start_file(file_name.as_str(), Default::default())?;
+      copy_decompressed(storage, &join(path, &file_name), &mut zip)?;
     }
   }
-  let res = zip.finish()?;
-  Ok(res.get_ref().to_vec())
+  zip.finish()?;
+  Ok(())
 }
 
-fn get_files_from_folder(dir_path: &str, relative_path: &str, zip: &mut ZipWriter<Cursor<Vec<u8>>>) -> Result<(), Error> {
-  let dir = std::fs::read_dir(format!("{}/{}", dir_path, relative_path))?;
+fn get_files_from_folder(storage: &dyn Storage, dir_path: &str, relative_path: &str, zip: &mut ZipWriter<File>) -> Result<(), Error> {
+  let dir = task::block_on(storage.list(&join(dir_path, relative_path)))?;
   for entry in dir {
-    let entry = entry?;
-    let path = entry.path();
-    let relative_path = path.strip_prefix(dir_path).unwrap().to_str().unwrap();
-    if path.is_dir() {
-      zip.add_directory(relative_path, Default::default())?;
-      get_files_from_folder(dir_path, relative_path, zip)?;
+    let relative_path = join(relative_path, &entry.name);
+    if entry.dir {
+      zip.add_directory(relative_path.as_str(), Default::default())?;
+      get_files_from_folder(storage, dir_path, &relative_path, zip)?;
     } else {
-      let mut file = File::open(&path)?;
-      let mut data = Vec::new();
-      file.read_to_end(&mut data)?;
-      let mut decoder = GzDecoder::new(&data[..]);
-      let mut decomp = Vec::new();
-      decoder.read_to_end(&mut decomp).unwrap();
-      let mut cursor = Cursor::new(decomp);
-      
-      zip.start_file(relative_path, Default::default())?;
-      std::io::copy(&mut cursor, zip)?;
+      zip.start_file(relative_path.as_str(), Default::default())?;
+      copy_decompressed(storage, &join(dir_path, &relative_path), zip)?;
     }
   }
 
   Ok(())
 }
 
+fn copy_decompressed(storage: &dyn Storage, path: &str, zip: &mut ZipWriter<File>) -> Result<(), Error> {
+  task::block_on(async {
+    let mut reader = decompress_file(storage, path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+      let len = reader.read(&mut buf).await?;
+      if len == 0 {
+        return Ok(());
+      }
+      zip.write_all(&buf[..len])?;
+    }
+  })
+}
+
 #[derive(Serialize, Deserialize)]
 struct Access {
   id: String,
@@ -365,6 +586,9 @@ struct Access {
 
 #[derive(Serialize, Deserialize)]
 struct AccessCreate {
+  // chosen here, so the audit log can name the record like updates and deletes do
+  #[serde(skip_deserializing)]
+  id: String,
   user: String,
   dir: String,
   write: bool,
@@ -386,6 +610,7 @@ struct AccessUpdate {
 #[derive(Serialize)]
 struct CloudFiles {
   files: Vec<CloudFile>,
+  total: usize,
 }
 
 #[derive(Serialize)]
@@ -394,11 +619,51 @@ struct CloudFileTemp {
   dir: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct CloudFile {
   name: String,
   dir: bool,
   write: bool,
+  size: Option<u64>,
+  compressed_size: Option<u64>,
+  modified: Option<u64>,
+  created: Option<u64>,
+  mime: Option<String>,
+  children: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+  #[serde(default)]
+  sort: SortKey,
+  #[serde(default)]
+  order: SortOrder,
+  page: Option<usize>,
+  per_page: Option<usize>,
+}
+
+#[derive(Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+  #[default]
+  Name,
+  Size,
+  Modified,
+  Created,
+  Type,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+  #[default]
+  Asc,
+  Desc,
+}
+
+#[derive(Deserialize)]
+struct Rename {
+  name: String,
 }
 
 #[derive(Serialize)]
@@ -416,4 +681,102 @@ impl ModifyRecord for AccessUpdate {
   fn id(&self) -> &String {
     &self.id
   } 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::test_support::TestApp;
+
+  #[async_std::test]
+  async fn stores_and_serves_files() {
+    let app = TestApp::new();
+    let res = app.post("bob", "cloud/files/test/hello.txt").body("hello").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let mut res = app.get("bob", "cloud/files/test/hello.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body_string().await.unwrap(), "hello");
+
+    let mut res = app.get("bob", "cloud/dirs/test").await.unwrap();
+    let listing: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(listing["files"][0]["name"], "hello.txt");
+    assert_eq!(listing["files"][0]["size"], 5);
+  }
+
+  #[async_std::test]
+  async fn sorts_and_pages_listings() {
+    let app = TestApp::new();
+    for (name, content) in [("b.txt", "bbbbbbbbbb"), ("a.png", "aaa"), ("c.txt", "c"), ("sub/d.txt", "d")] {
+      let res = app.post("bob", format!("cloud/files/test/{}", name)).body(content).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    let names = |query: &'static str| {
+      let app = app.clone();
+      async move {
+        let mut res = app.get("bob", format!("cloud/dirs/test?{}", query)).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let listing: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(listing["total"], 4);
+        listing["files"].as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+      }
+    };
+
+    // directories always come first, whatever the order
+    assert_eq!(names("").await, ["sub", "a.png", "b.txt", "c.txt"]);
+    assert_eq!(names("order=desc").await, ["sub", "c.txt", "b.txt", "a.png"]);
+    assert_eq!(names("sort=size").await, ["sub", "c.txt", "a.png", "b.txt"]);
+    assert_eq!(names("sort=size&order=desc").await, ["sub", "b.txt", "a.png", "c.txt"]);
+    assert_eq!(names("sort=type").await, ["sub", "a.png", "b.txt", "c.txt"]);
+
+    assert_eq!(names("per_page=2").await, ["sub", "a.png"]);
+    assert_eq!(names("per_page=2&page=2").await, ["b.txt", "c.txt"]);
+    assert_eq!(names("sort=size&per_page=3&page=2").await, ["b.txt"]);
+    assert!(names("per_page=2&page=3").await.is_empty());
+    // the page that is returned still gets its metadata when sorting by name
+    let mut res = app.get("bob", "cloud/dirs/test?per_page=1&page=2").await.unwrap();
+    let listing: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!((listing["files"][0]["size"].as_u64(), listing["files"][0]["mime"].as_str()), (Some(3), Some("image/png")));
+    let res = app.get("bob", "cloud/dirs/test?sort=owner").await.unwrap();
+    assert_eq!(res.status(), 400);
+  }
+
+  #[async_std::test]
+  async fn serves_ranges_and_revalidates() {
+    let app = TestApp::new();
+    let res = app.post("bob", "cloud/files/test/digits.txt").body("0123456789abcdef").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = app.get("bob", "cloud/files/test/digits.txt").await.unwrap();
+    assert_eq!(res.header("Accept-Ranges").unwrap().as_str(), "bytes");
+    let etag = res.header("ETag").unwrap().as_str().to_string();
+
+    let mut res = app.get("bob", "cloud/files/test/digits.txt").header("Range", "bytes=2-5").await.unwrap();
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.header("Content-Range").unwrap().as_str(), "bytes 2-5/16");
+    assert_eq!(res.body_string().await.unwrap(), "2345");
+    let mut res = app.get("bob", "cloud/files/test/digits.txt").header("Range", "bytes=-3").await.unwrap();
+    assert_eq!(res.body_string().await.unwrap(), "def");
+    let res = app.get("bob", "cloud/files/test/digits.txt").header("Range", "bytes=16-").await.unwrap();
+    assert_eq!(res.status(), 416);
+    assert_eq!(res.header("Content-Range").unwrap().as_str(), "bytes */16");
+
+    let res = app.get("bob", "cloud/files/test/digits.txt").header("If-None-Match", etag.as_str()).await.unwrap();
+    assert_eq!(res.status(), 304);
+    let mut res = app.get("bob", "cloud/files/test/digits.txt").header("Range", "bytes=10-").header("If-Range", etag.as_str()).await.unwrap();
+    assert_eq!(res.status(), 206);
+    assert_eq!(res.body_string().await.unwrap(), "abcdef");
+    // a stale validator means the client's copy is outdated, so it gets the whole file
+    let mut res = app.get("bob", "cloud/files/test/digits.txt").header("Range", "bytes=10-").header("If-Range", "\"outdated\"").await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body_string().await.unwrap(), "0123456789abcdef");
+  }
+
+  #[async_std::test]
+  async fn keeps_writes_inside_granted_dirs() {
+    let app = TestApp::new();
+    let res = app.post("bob", "cloud/files/other/hello.txt").body("hello").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let res = app.post("bob", "cloud/files/test/..%2F..%2Fescape.txt").body("hello").await.unwrap();
+    assert_eq!(res.status(), 400);
+  }
+}
@@ -0,0 +1,228 @@
+use std::{fmt, ops::Deref};
+
+use percent_encoding::percent_decode_str;
+use tide::Request;
+
+use crate::{cloud::SYSTEM_DIR, error::ApiError, state::AppState, storage::join};
+
+const MAX_NAME_LEN: usize = 255;
+const MAX_PATH_LEN: usize = 4096;
+
+// a canonical path relative to the cloud root, without empty, "." or ".." segments and never
+// pointing into the system directory, so it can be handed to the storage as is
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CloudPath(String);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PathError {
+  Encoding,
+  Absolute,
+  Escape,
+  IllegalName,
+  TooLong,
+  Reserved,
+}
+
+impl CloudPath {
+  pub(crate) fn parse(raw: &str) -> Result<Self, PathError> {
+    if raw.len() > MAX_PATH_LEN {
+      return Err(PathError::TooLong);
+    }
+    if raw.starts_with('/') {
+      return Err(PathError::Absolute);
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in raw.split('/') {
+      match segment {
+        "" | "." => (),
+        ".." => {
+          if segments.pop().is_none() {
+            return Err(PathError::Escape);
+          }
+        },
+        name => {
+          check_name(name)?;
+          segments.push(name);
+        },
+      }
+    }
+
+    if segments.first() == Some(&SYSTEM_DIR) {
+      return Err(PathError::Reserved);
+    }
+    Ok(CloudPath(segments.join("/")))
+  }
+
+  pub(crate) fn from_param(req: &Request<AppState>, name: &str) -> Result<Self, PathError> {
+    let raw = percent_decode_str(req.param(name).unwrap_or_default()).decode_utf8().map_err(|_| PathError::Encoding)?;
+    Self::parse(&raw)
+  }
+
+  pub(crate) fn is_root(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  pub(crate) fn name(&self) -> &str {
+    self.0.rsplit('/').next().unwrap_or_default()
+  }
+
+  pub(crate) fn parent(&self) -> CloudPath {
+    CloudPath(self.0.rsplit_once('/').map(|(p, _)| p.to_string()).unwrap_or_default())
+  }
+
+  // a relative path below this one, it may not leave it through ".."
+  pub(crate) fn join(&self, relative: &str) -> Result<CloudPath, PathError> {
+    if relative.starts_with('/') {
+      return Err(PathError::Absolute);
+    }
+    let path = CloudPath::parse(&join(&self.0, relative))?;
+    if path == *self || !self.is_root() && !path.is_within(self) {
+      return Err(PathError::Escape);
+    }
+    Ok(path)
+  }
+
+  // a sibling with a different name, used for renames
+  pub(crate) fn with_name(&self, name: &str) -> Result<CloudPath, PathError> {
+    if self.is_root() {
+      return Err(PathError::IllegalName);
+    }
+    check_name(name)?;
+    self.parent().join(name)
+  }
+
+  // the root only contains itself, every other path contains all paths below it
+  pub(crate) fn is_within(&self, ancestor: &CloudPath) -> bool {
+    self.0 == ancestor.0 || self.0.starts_with(&format!("{}/", ancestor.0))
+  }
+}
+
+impl Deref for CloudPath {
+  type Target = str;
+
+  fn deref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl fmt::Display for CloudPath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl PathError {
+  pub(crate) fn response(self) -> tide::Response {
+    ApiError::from(self).response()
+  }
+}
+
+fn check_name(name: &str) -> Result<(), PathError> {
+  if name.len() > MAX_NAME_LEN {
+    return Err(PathError::TooLong);
+  }
+  if name == "." || name == ".." || name.contains(['/', '\\']) || name.chars().any(|c| c.is_control()) {
+    return Err(PathError::IllegalName);
+  }
+  // trailing dots and spaces are stripped by some filesystems, which would alias other names
+  if name.ends_with(['.', ' ']) || name.starts_with(' ') {
+    return Err(PathError::IllegalName);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(raw: &str) -> Result<String, PathError> {
+    CloudPath::parse(raw).map(|p| p.0)
+  }
+
+  #[test]
+  fn canonicalizes_paths() {
+    assert_eq!(parse(""), Ok("".to_string()));
+    assert_eq!(parse("a/b/c"), Ok("a/b/c".to_string()));
+    assert_eq!(parse("a//b/./c/"), Ok("a/b/c".to_string()));
+    assert_eq!(parse("a/b/../c"), Ok("a/c".to_string()));
+    assert_eq!(parse("a/.."), Ok("".to_string()));
+    assert_eq!(parse("./a"), Ok("a".to_string()));
+    assert_eq!(parse("sub dir/ünïcödé.txt"), Ok("sub dir/ünïcödé.txt".to_string()));
+    assert_eq!(parse(".hidden/..x"), Ok(".hidden/..x".to_string()));
+  }
+
+  #[test]
+  fn rejects_escapes() {
+    for raw in ["..", "../", "../etc/passwd", "a/../..", "a/../../b", "./../a", "a/b/../../../c", ".//../x"] {
+      assert_eq!(parse(raw), Err(PathError::Escape), "{}", raw);
+    }
+  }
+
+  #[test]
+  fn rejects_absolute_paths() {
+    for raw in ["/", "/etc/passwd", "//server/share", "/a/../b"] {
+      assert_eq!(parse(raw), Err(PathError::Absolute), "{}", raw);
+    }
+  }
+
+  #[test]
+  fn rejects_illegal_names() {
+    for raw in ["a\\..\\b", "..\\..\\x", "\\windows", "C:\\Windows", "a\0b", "....", "a/...", "a/b\nc", "tab\there", "trailing.", "trailing ", " leading", "a/\u{7f}"] {
+      assert_eq!(parse(raw), Err(PathError::IllegalName), "{:?}", raw);
+    }
+    assert_eq!(parse(&"x".repeat(256)), Err(PathError::TooLong));
+    assert_eq!(parse(&"a/".repeat(3000)), Err(PathError::TooLong));
+  }
+
+  #[test]
+  fn rejects_system_dir() {
+    for raw in [".cloud", ".cloud/versions/1", "a/../.cloud", "./.cloud/trash"] {
+      assert_eq!(parse(raw), Err(PathError::Reserved), "{}", raw);
+    }
+    assert!(parse("a/.cloud").is_ok());
+  }
+
+  #[test]
+  fn percent_decoding_happens_once() {
+    // a double encoded dot is only a literal name, invalid utf-8 is rejected instead of replaced
+    let decoded = percent_decode_str("%252e%252e/x").decode_utf8().unwrap();
+    assert_eq!(parse(&decoded), Ok("%2e%2e/x".to_string()));
+    let decoded = percent_decode_str("%2e%2e/x").decode_utf8().unwrap();
+    assert_eq!(parse(&decoded), Err(PathError::Escape));
+    assert!(percent_decode_str("%ff%fe").decode_utf8().is_err());
+  }
+
+  #[test]
+  fn joins_stay_below() {
+    let dir = CloudPath::parse("a/b").unwrap();
+    assert_eq!(dir.join("c/d").map(|p| p.0), Ok("a/b/c/d".to_string()));
+    assert_eq!(dir.join("c/../d").map(|p| p.0), Ok("a/b/d".to_string()));
+    assert_eq!(dir.join("..").map(|p| p.0), Err(PathError::Escape));
+    assert_eq!(dir.join("../c").map(|p| p.0), Err(PathError::Escape));
+    assert_eq!(dir.join("").map(|p| p.0), Err(PathError::Escape));
+    assert_eq!(dir.join("/etc").map(|p| p.0), Err(PathError::Absolute));
+    assert_eq!(CloudPath::default().join("../x"), Err(PathError::Escape));
+    assert_eq!(CloudPath::default().join(".cloud"), Err(PathError::Reserved));
+  }
+
+  #[test]
+  fn renames_stay_in_the_parent() {
+    let file = CloudPath::parse("a/b.txt").unwrap();
+    assert_eq!(file.with_name("c.txt").map(|p| p.0), Ok("a/c.txt".to_string()));
+    for name in ["", ".", "..", "../c", "c/d", "..\\c", "c\0"] {
+      assert!(file.with_name(name).is_err(), "{:?}", name);
+    }
+    assert!(CloudPath::default().with_name("x").is_err());
+    assert_eq!(CloudPath::parse("a").unwrap().with_name(".cloud"), Err(PathError::Reserved));
+  }
+
+  #[test]
+  fn containment_is_per_segment() {
+    let dir = CloudPath::parse("test").unwrap();
+    assert!(CloudPath::parse("test/a").unwrap().is_within(&dir));
+    assert!(dir.is_within(&dir));
+    assert!(!CloudPath::parse("test2/a").unwrap().is_within(&dir));
+    assert!(!CloudPath::parse("tes").unwrap().is_within(&dir));
+  }
+}
@@ -0,0 +1,600 @@
+use std::{fmt, net::SocketAddr, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use surf::Url;
+
+const DEFAULT_FILE: &str = "config.toml";
+const REDACTED: &str = "<redacted>";
+const STORAGE_BACKENDS: [&str; 3] = ["local", "memory", "s3"];
+
+// everything that can be configured, read once at startup and shared through the app state
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+  pub(crate) listen: String,
+  pub(crate) cors_origins: Vec<String>,
+  pub(crate) pocketbase: PocketBaseConfig,
+  pub(crate) auth: AuthConfig,
+  pub(crate) oidc: OidcConfig,
+  pub(crate) rate_limit: RateLimitConfig,
+  pub(crate) prometheus: PrometheusConfig,
+  pub(crate) nasa: NasaConfig,
+  pub(crate) iframe: IframeConfig,
+  pub(crate) cloud: CloudConfig,
+  pub(crate) s3: S3Config,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PocketBaseConfig {
+  pub(crate) url: String,
+  pub(crate) email: String,
+  pub(crate) password: String,
+  pub(crate) timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthConfig {
+  // seconds a validated token is trusted before pocketbase is asked again, 0 disables the cache
+  pub(crate) cache_ttl: u64,
+}
+
+// an external identity provider, its users are matched to pocketbase users by email
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OidcConfig {
+  // empty disables oidc logins
+  pub(crate) issuer: String,
+  pub(crate) audience: String,
+  // discovered from the issuer when empty
+  pub(crate) jwks_url: String,
+  pub(crate) timeout: u64,
+  // seconds the signing keys are used before they are fetched again
+  pub(crate) jwks_refresh: u64,
+  pub(crate) create_users: bool,
+  pub(crate) rules: Vec<OidcRule>,
+}
+
+// grants the permission bits when the claim equals the value or is a list containing it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OidcRule {
+  // nested claims are separated by dots, like realm_access.roles
+  pub(crate) claim: String,
+  pub(crate) value: String,
+  pub(crate) permissions: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+  pub(crate) enabled: bool,
+  // take the client address from the forwarded headers, only safe behind a proxy that sets them
+  pub(crate) trust_proxy: bool,
+  pub(crate) per_ip: Limit,
+  pub(crate) per_user: Limit,
+  // stricter limits per client address for routes matching the prefix
+  pub(crate) groups: Vec<GroupLimit>,
+  // this many 401s within the window lock the address out, times in seconds
+  pub(crate) max_auth_failures: u32,
+  pub(crate) failure_window: u64,
+  pub(crate) lockout: u64,
+}
+
+// a token bucket refilled with rate tokens per second that holds up to burst of them
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limit {
+  pub(crate) rate: f64,
+  pub(crate) burst: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GroupLimit {
+  pub(crate) prefix: String,
+  pub(crate) rate: f64,
+  pub(crate) burst: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PrometheusConfig {
+  pub(crate) url: String,
+  pub(crate) timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NasaConfig {
+  pub(crate) url: String,
+  pub(crate) api_key: String,
+  pub(crate) timeout: u64,
+}
+
+// targets the frontend embeds, empty when not deployed
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IframeConfig {
+  pub(crate) portainer: String,
+  pub(crate) pocketbase: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CloudConfig {
+  pub(crate) storage: String,
+  pub(crate) dir: String,
+  // resumable uploads and temporary files, below the cloud volume by default so sessions survive a restart
+  pub(crate) upload_dir: String,
+  pub(crate) max_versions: usize,
+  // days, 0 keeps them forever
+  pub(crate) version_max_age: u64,
+  pub(crate) trash_max_age: u64,
+  // days after which an unfinished upload is dropped, 0 keeps them
+  pub(crate) upload_max_age: u64,
+  pub(crate) url: String,
+}
+
+// only used when cloud.storage is s3, any S3 compatible store addressed with path style requests
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct S3Config {
+  pub(crate) endpoint: String,
+  pub(crate) bucket: String,
+  pub(crate) region: String,
+  pub(crate) access_key: String,
+  pub(crate) secret_key: String,
+  // keys are stored below it, so several instances can share a bucket
+  pub(crate) prefix: String,
+  pub(crate) timeout: u64,
+}
+
+// collects every problem so they can all be fixed in one go
+#[derive(Debug, Default)]
+pub(crate) struct ConfigError(Vec<String>);
+
+#[derive(Default)]
+pub(crate) struct Args {
+  pub(crate) config: Option<String>,
+  pub(crate) print_config: bool,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      listen: "0.0.0.0:8080".to_string(),
+      cors_origins: vec!["*".to_string()],
+      pocketbase: PocketBaseConfig::default(),
+      auth: AuthConfig::default(),
+      oidc: OidcConfig::default(),
+      rate_limit: RateLimitConfig::default(),
+      prometheus: PrometheusConfig::default(),
+      nasa: NasaConfig::default(),
+      iframe: IframeConfig::default(),
+      cloud: CloudConfig::default(),
+      s3: S3Config::default(),
+    }
+  }
+}
+
+impl Default for PocketBaseConfig {
+  fn default() -> Self {
+    PocketBaseConfig { url: "localhost:8090".to_string(), email: String::new(), password: String::new(), timeout: 10 }
+  }
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    AuthConfig { cache_ttl: 60 }
+  }
+}
+
+impl Default for OidcConfig {
+  fn default() -> Self {
+    OidcConfig {
+      issuer: String::new(),
+      audience: String::new(),
+      jwks_url: String::new(),
+      timeout: 10,
+      jwks_refresh: 60 * 60,
+      create_users: false,
+      rules: Vec::new(),
+    }
+  }
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    // the public routes are where links and tokens could be guessed
+    let public = |prefix: &str| GroupLimit { prefix: prefix.to_string(), rate: 1.0, burst: 30 };
+    RateLimitConfig {
+      enabled: true,
+      trust_proxy: false,
+      per_ip: Limit { rate: 50.0, burst: 200 },
+      per_user: Limit { rate: 20.0, burst: 100 },
+      groups: vec![public("/cloud/direct/"), public("/images/apod/direct/")],
+      max_auth_failures: 10,
+      failure_window: 5 * 60,
+      lockout: 15 * 60,
+    }
+  }
+}
+
+impl Default for PrometheusConfig {
+  fn default() -> Self {
+    PrometheusConfig { url: "http://localhost:9090".to_string(), timeout: 30 }
+  }
+}
+
+impl Default for NasaConfig {
+  fn default() -> Self {
+    NasaConfig { url: "https://api.nasa.gov".to_string(), api_key: "DEMO_KEY".to_string(), timeout: 15 }
+  }
+}
+
+impl Default for CloudConfig {
+  fn default() -> Self {
+    CloudConfig {
+      storage: "local".to_string(),
+      dir: "cloud".to_string(),
+      upload_dir: "cloud/.cloud/uploads".to_string(),
+      max_versions: 10,
+      version_max_age: 30,
+      trash_max_age: 30,
+      upload_max_age: 7,
+      url: "https://api.profidev.io/cloud/direct".to_string(),
+    }
+  }
+}
+
+impl Default for S3Config {
+  fn default() -> Self {
+    S3Config {
+      endpoint: "http://localhost:9000".to_string(),
+      bucket: "cloud".to_string(),
+      region: "us-east-1".to_string(),
+      access_key: String::new(),
+      secret_key: String::new(),
+      prefix: String::new(),
+      timeout: 60,
+    }
+  }
+}
+
+impl Args {
+  pub(crate) fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--print-config" => parsed.print_config = true,
+        "--config" => match args.next() {
+          Some(path) => parsed.config = Some(path),
+          None => return Err(ConfigError(vec!["--config needs a file path".to_string()])),
+        },
+        _ => return Err(ConfigError(vec![format!("unknown argument {}, expected --config <file> or --print-config", arg)])),
+      }
+    }
+    Ok(parsed)
+  }
+}
+
+impl Config {
+  // the file is optional unless it was asked for explicitly, env vars override whatever it sets
+  pub(crate) fn load(args: &Args) -> Result<Self, ConfigError> {
+    let path = args.config.clone().or_else(|| std::env::var("CONFIG_FILE").ok());
+    let mut config = match &path {
+      Some(path) => Config::from_file(path)?,
+      None if Path::new(DEFAULT_FILE).exists() => Config::from_file(DEFAULT_FILE)?,
+      None => Config::default(),
+    };
+
+    config.apply_env(|name| std::env::var(name).ok())?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn from_file(path: &str) -> Result<Self, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError(vec![format!("{}: {}", path, e)]))?;
+    let parsed = match Path::new(path).extension().and_then(|e| e.to_str()) {
+      Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+      Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+      _ => Err("unknown format, use a .toml, .yaml or .yml file".to_string()),
+    };
+    parsed.map_err(|e| ConfigError(vec![format!("{}: {}", path, e.trim_end())]))
+  }
+
+  // the variable names predate the config file and keep working
+  fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    let mut set = |name: &str, apply: &mut dyn FnMut(String) -> Result<(), String>| {
+      if let Some(value) = var(name) {
+        if let Err(e) = apply(value) {
+          errors.push(format!("{}: {}", name, e));
+        }
+      }
+    };
+
+    set("LISTEN_ADDR", &mut |v| parse(&v, &mut self.listen));
+    set("CORS_ORIGINS", &mut |v| {
+      self.cors_origins = v.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect();
+      Ok(())
+    });
+    set("PB_URL", &mut |v| parse(&v, &mut self.pocketbase.url));
+    set("PB_EMAIL", &mut |v| parse(&v, &mut self.pocketbase.email));
+    set("PB_PASSWORD", &mut |v| parse(&v, &mut self.pocketbase.password));
+    set("PB_TIMEOUT", &mut |v| parse(&v, &mut self.pocketbase.timeout));
+    set("AUTH_CACHE_TTL", &mut |v| parse(&v, &mut self.auth.cache_ttl));
+    set("OIDC_ISSUER", &mut |v| parse(&v, &mut self.oidc.issuer));
+    set("OIDC_AUDIENCE", &mut |v| parse(&v, &mut self.oidc.audience));
+    set("OIDC_JWKS_URL", &mut |v| parse(&v, &mut self.oidc.jwks_url));
+    set("RATE_LIMIT_ENABLED", &mut |v| parse(&v, &mut self.rate_limit.enabled));
+    set("TRUST_PROXY", &mut |v| parse(&v, &mut self.rate_limit.trust_proxy));
+    set("METRICS_HOST", &mut |v| parse(&format!("{}:9090", v), &mut self.prometheus.url));
+    set("METRICS_TIMEOUT", &mut |v| parse(&v, &mut self.prometheus.timeout));
+    set("NASA_API_KEY", &mut |v| parse(&v, &mut self.nasa.api_key));
+    set("NASA_TIMEOUT", &mut |v| parse(&v, &mut self.nasa.timeout));
+    set("PORTAINER_URL", &mut |v| parse(&v, &mut self.iframe.portainer));
+    set("POCKETBASE_URL", &mut |v| parse(&v, &mut self.iframe.pocketbase));
+    set("CLOUD_STORAGE", &mut |v| parse(&v, &mut self.cloud.storage));
+    set("CLOUD_DIR", &mut |v| parse(&v, &mut self.cloud.dir));
+    set("CLOUD_UPLOAD_DIR", &mut |v| parse(&v, &mut self.cloud.upload_dir));
+    set("CLOUD_MAX_VERSIONS", &mut |v| parse(&v, &mut self.cloud.max_versions));
+    set("CLOUD_VERSION_MAX_AGE", &mut |v| parse(&v, &mut self.cloud.version_max_age));
+    set("CLOUD_TRASH_MAX_AGE", &mut |v| parse(&v, &mut self.cloud.trash_max_age));
+    set("CLOUD_UPLOAD_MAX_AGE", &mut |v| parse(&v, &mut self.cloud.upload_max_age));
+    set("CLOUD_URL", &mut |v| parse(&v, &mut self.cloud.url));
+    set("S3_ENDPOINT", &mut |v| parse(&v, &mut self.s3.endpoint));
+    set("S3_BUCKET", &mut |v| parse(&v, &mut self.s3.bucket));
+    set("S3_REGION", &mut |v| parse(&v, &mut self.s3.region));
+    set("S3_ACCESS_KEY", &mut |v| parse(&v, &mut self.s3.access_key));
+    set("S3_SECRET_KEY", &mut |v| parse(&v, &mut self.s3.secret_key));
+    set("S3_PREFIX", &mut |v| parse(&v, &mut self.s3.prefix));
+    set("S3_TIMEOUT", &mut |v| parse(&v, &mut self.s3.timeout));
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(ConfigError(errors))
+    }
+  }
+
+  pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+    let mut errors = Vec::new();
+    if self.listen.parse::<SocketAddr>().is_err() {
+      errors.push(format!("listen: {} is not a socket address like 0.0.0.0:8080", self.listen));
+    }
+    if self.cors_origins.is_empty() {
+      errors.push("cors_origins: at least one origin is required, use \"*\" to allow all".to_string());
+    } else if self.cors_origins.len() > 1 && self.cors_origins.iter().any(|o| o == "*") {
+      errors.push("cors_origins: \"*\" can't be combined with other origins".to_string());
+    }
+
+    check_url(&mut errors, "pocketbase.url", &self.pocketbase.url, true);
+    if self.pocketbase.email.is_empty() || self.pocketbase.password.is_empty() {
+      errors.push("pocketbase: email and password of an admin account are required".to_string());
+    }
+    if !self.oidc.issuer.is_empty() {
+      check_url(&mut errors, "oidc.issuer", &self.oidc.issuer, true);
+      check_url(&mut errors, "oidc.jwks_url", &self.oidc.jwks_url, false);
+      if self.oidc.audience.is_empty() {
+        errors.push("oidc.audience: required when an issuer is set".to_string());
+      }
+    }
+    for (i, rule) in self.oidc.rules.iter().enumerate() {
+      if rule.claim.is_empty() || rule.permissions < 0 {
+        errors.push(format!("oidc.rules[{}]: needs a claim and permissions of at least 0", i));
+      }
+    }
+    let limits = [("rate_limit.per_ip", self.rate_limit.per_ip.rate, self.rate_limit.per_ip.burst), ("rate_limit.per_user", self.rate_limit.per_user.rate, self.rate_limit.per_user.burst)];
+    let groups = self.rate_limit.groups.iter().map(|g| ("rate_limit.groups", g.rate, g.burst));
+    for (name, rate, burst) in limits.into_iter().chain(groups) {
+      if !(rate > 0.0 && rate.is_finite()) || burst == 0 {
+        errors.push(format!("{}: rate and burst must be greater than 0", name));
+      }
+    }
+    // with 0 every request would count as one failure too many
+    if self.rate_limit.max_auth_failures == 0 {
+      errors.push("rate_limit.max_auth_failures: must be at least 1".to_string());
+    }
+    check_url(&mut errors, "prometheus.url", &self.prometheus.url, true);
+    check_url(&mut errors, "nasa.url", &self.nasa.url, true);
+    check_url(&mut errors, "iframe.portainer", &self.iframe.portainer, false);
+    check_url(&mut errors, "iframe.pocketbase", &self.iframe.pocketbase, false);
+    check_url(&mut errors, "cloud.url", &self.cloud.url, true);
+    for (name, timeout) in [("pocketbase.timeout", self.pocketbase.timeout), ("oidc.timeout", self.oidc.timeout), ("prometheus.timeout", self.prometheus.timeout), ("nasa.timeout", self.nasa.timeout)] {
+      if timeout == 0 {
+        errors.push(format!("{}: must be at least 1 second", name));
+      }
+    }
+
+    if !STORAGE_BACKENDS.contains(&self.cloud.storage.as_str()) {
+      errors.push(format!("cloud.storage: {} is not one of {}", self.cloud.storage, STORAGE_BACKENDS.join(", ")));
+    }
+    if self.cloud.storage == "local" && self.cloud.dir.is_empty() {
+      errors.push("cloud.dir: required for local storage".to_string());
+    }
+    if self.cloud.upload_dir.is_empty() {
+      errors.push("cloud.upload_dir: required".to_string());
+    }
+    if self.cloud.storage == "s3" {
+      // requests are signed with the host, so the scheme can't be left out here
+      if !self.s3.endpoint.contains("://") {
+        errors.push(format!("s3.endpoint: {} needs a scheme like https://", self.s3.endpoint));
+      } else {
+        check_url(&mut errors, "s3.endpoint", &self.s3.endpoint, true);
+      }
+      for (name, value) in [("s3.bucket", &self.s3.bucket), ("s3.region", &self.s3.region), ("s3.access_key", &self.s3.access_key), ("s3.secret_key", &self.s3.secret_key)] {
+        if value.is_empty() {
+          errors.push(format!("{}: required for s3 storage", name));
+        }
+      }
+      if self.s3.bucket.contains('/') {
+        errors.push(format!("s3.bucket: {} is a bucket name, not a path", self.s3.bucket));
+      }
+      if self.s3.timeout == 0 {
+        errors.push("s3.timeout: must be at least 1 second".to_string());
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(ConfigError(errors))
+    }
+  }
+
+  pub(crate) fn redacted(&self) -> Config {
+    let mut config = self.clone();
+    for secret in [&mut config.pocketbase.password, &mut config.nasa.api_key, &mut config.s3.access_key, &mut config.s3.secret_key] {
+      if !secret.is_empty() {
+        *secret = REDACTED.to_string();
+      }
+    }
+    config
+  }
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "invalid configuration:")?;
+    for error in &self.0 {
+      writeln!(f, "  - {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+fn parse<T: FromStr>(value: &str, target: &mut T) -> Result<(), String> where T::Err: fmt::Display {
+  *target = value.parse().map_err(|e| format!("{} ({})", e, value))?;
+  Ok(())
+}
+
+// upstream hosts may be given without a scheme, http is assumed for them
+fn check_url(errors: &mut Vec<String>, name: &str, url: &str, required: bool) {
+  if url.is_empty() {
+    if required {
+      errors.push(format!("{}: required", name));
+    }
+    return;
+  }
+  let full = if url.contains("://") { url.to_string() } else { format!("http://{}", url) };
+  if Url::parse(&full).map(|u| u.host_str().is_none()).unwrap_or(true) {
+    errors.push(format!("{}: {} is not a valid url", name, url));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+
+  fn valid() -> Config {
+    let mut config = Config::default();
+    config.pocketbase.email = "admin@example.com".to_string();
+    config.pocketbase.password = "secret".to_string();
+    config
+  }
+
+  fn errors(config: &Config) -> Vec<String> {
+    config.validate().err().map(|e| e.0).unwrap_or_default()
+  }
+
+  #[test]
+  fn reads_toml_and_yaml() {
+    let toml: Config = toml::from_str("listen = \"127.0.0.1:9000\"\n[cloud]\ndir = \"/srv/cloud\"\n").unwrap();
+    assert_eq!(toml.listen, "127.0.0.1:9000");
+    assert_eq!(toml.cloud.dir, "/srv/cloud");
+    assert_eq!(toml.cloud.max_versions, 10);
+
+    let yaml: Config = serde_yaml::from_str("cors_origins: [https://profidev.io]\npocketbase:\n  timeout: 3\n").unwrap();
+    assert_eq!(yaml.cors_origins, vec!["https://profidev.io"]);
+    assert_eq!(yaml.pocketbase.timeout, 3);
+    assert_eq!(yaml.pocketbase.url, "localhost:8090");
+  }
+
+  #[test]
+  fn reads_oidc_rules() {
+    let config: Config = toml::from_str("[oidc]\nissuer = \"https://id.example.com/realms/main\"\naudience = \"backend\"\n[[oidc.rules]]\nclaim = \"realm_access.roles\"\nvalue = \"cloud\"\npermissions = 8\n").unwrap();
+    assert_eq!(config.oidc.rules[0].claim, "realm_access.roles");
+    assert_eq!(config.oidc.jwks_refresh, 3600);
+
+    let mut config = valid();
+    config.oidc.issuer = "https://id.example.com".to_string();
+    assert_eq!(errors(&config), vec!["oidc.audience: required when an issuer is set"]);
+  }
+
+  #[test]
+  fn rejects_unknown_keys() {
+    assert!(toml::from_str::<Config>("[cloud]\nmax_version = 3\n").is_err());
+  }
+
+  #[test]
+  fn env_overrides_the_file() {
+    let env: HashMap<&str, &str> = [("PB_TIMEOUT", "5"), ("CORS_ORIGINS", "https://a.io, https://b.io"), ("METRICS_HOST", "http://prom")].into();
+    let mut config = valid();
+    config.apply_env(|name| env.get(name).map(|v| v.to_string())).unwrap();
+    assert_eq!(config.pocketbase.timeout, 5);
+    assert_eq!(config.cors_origins, vec!["https://a.io", "https://b.io"]);
+    assert_eq!(config.prometheus.url, "http://prom:9090");
+
+    let env: HashMap<&str, &str> = [("PB_TIMEOUT", "soon"), ("CLOUD_MAX_VERSIONS", "-1")].into();
+    let err = config.apply_env(|name| env.get(name).map(|v| v.to_string())).unwrap_err();
+    assert_eq!(err.0.len(), 2);
+    assert!(err.0[0].starts_with("PB_TIMEOUT"));
+  }
+
+  #[test]
+  fn validates_values() {
+    assert!(errors(&valid()).is_empty());
+    assert_eq!(errors(&Config::default()).len(), 1);
+
+    let mut config = valid();
+    config.listen = "8080".to_string();
+    config.cors_origins = vec!["*".to_string(), "https://a.io".to_string()];
+    config.pocketbase.timeout = 0;
+    config.cloud.storage = "ftp".to_string();
+    config.cloud.url = "".to_string();
+    config.iframe.portainer = "http://".to_string();
+    config.rate_limit.max_auth_failures = 0;
+    let errors = errors(&config);
+    assert_eq!(errors.len(), 7, "{:?}", errors);
+    assert!(errors.iter().any(|e| e.starts_with("cloud.storage")));
+    assert!(errors.iter().any(|e| e.starts_with("rate_limit.max_auth_failures")));
+  }
+
+  #[test]
+  fn validates_s3_only_when_used() {
+    let mut config = valid();
+    config.s3.endpoint = "minio:9000".to_string();
+    assert!(errors(&config).is_empty());
+
+    config.cloud.storage = "s3".to_string();
+    let errors_found = errors(&config);
+    assert_eq!(errors_found.len(), 3, "{:?}", errors_found);
+    assert!(errors_found[0].starts_with("s3.endpoint"));
+
+    config.s3.endpoint = "http://minio:9000".to_string();
+    config.s3.access_key = "access".to_string();
+    config.s3.secret_key = "secret-key".to_string();
+    assert!(errors(&config).is_empty());
+
+    let env: HashMap<&str, &str> = [("S3_BUCKET", "files"), ("S3_TIMEOUT", "5")].into();
+    config.apply_env(|name| env.get(name).map(|v| v.to_string())).unwrap();
+    assert_eq!((config.s3.bucket.as_str(), config.s3.timeout), ("files", 5));
+  }
+
+  #[test]
+  fn redacts_secrets() {
+    let mut config = valid();
+    config.s3.access_key = "s3-access".to_string();
+    config.s3.secret_key = "s3-secret".to_string();
+    let printed = toml::to_string(&config.redacted()).unwrap();
+    assert!(!printed.contains("\"secret\""));
+    assert!(!printed.contains("DEMO_KEY"));
+    assert!(!printed.contains("s3-access") && !printed.contains("s3-secret"));
+    assert!(printed.contains("admin@example.com"));
+  }
+}
@@ -1,67 +1,229 @@
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use async_std::sync::{Mutex, RwLock};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use surf::{Client, Result};
+use surf::{Client, RequestBuilder, Result, StatusCode};
+
+use crate::{auth::decode_part, config::Config, error::ApiError};
+
+const MAX_PER_PAGE: u32 = 500;
+const DEFAULT_PER_PAGE: u32 = 50;
+const TOKEN_REFRESH_MARGIN: i64 = 5 * 60;
+const UNKNOWN_EXPIRY_REFRESH: i64 = 10 * 60;
+const MAX_RETRY_DELAY: u64 = 60;
+
+// the admin connection to pocketbase, the token is renewed in place when it expires
+pub(crate) struct Db {
+  client: Client,
+  email: String,
+  password: String,
+  token: RwLock<String>,
+  renewing: Mutex<()>,
+}
 
-#[derive(Deserialize, Debug)]
-struct RecordsResponse<T> {
-  items: Vec<T>,
+impl Db {
+  pub(crate) fn new(client: Client, config: &Config) -> Self {
+    Db {
+      client,
+      email: config.pocketbase.email.clone(),
+      password: config.pocketbase.password.clone(),
+      token: RwLock::new(String::new()),
+      renewing: Mutex::new(()),
+    }
+  }
 }
 
-pub(crate) async fn get_collection_records<T>(collection: &str, filter: Option<&str>) -> Result<Vec<T>> where T: DeserializeOwned {
+// reads every page, each one continues after the last id of the one before,
+// so records inserted or deleted in the meantime can't shift others between pages
+pub(crate) async fn get_collection_records<T>(db: &Db, collection: &str, filter: Option<Filter>) -> Result<Vec<T>> where T: DeserializeOwned {
+  let mut records = Vec::new();
+  let mut after: Option<String> = None;
+  loop {
+    let page_filter = match (&filter, after) {
+      (Some(f), Some(id)) => Some(f.clone().and(Filter::gt("id", id))),
+      (None, Some(id)) => Some(Filter::gt("id", id)),
+      (f, None) => f.clone(),
+    };
+    let res = get_collection_page::<serde_json::Value>(db, collection, page_filter, 1, MAX_PER_PAGE).await?;
+    after = res.items.last().and_then(|r| r["id"].as_str()).map(str::to_string);
+    for item in res.items {
+      records.push(serde_json::from_value(item).map_err(upstream)?);
+    }
+    if res.total_pages <= 1 || after.is_none() {
+      return Ok(records);
+    }
+  }
+}
+
+pub(crate) async fn get_collection_page<T>(db: &Db, collection: &str, filter: Option<Filter>, page: u32, per_page: u32) -> Result<Page<T>> where T: DeserializeOwned {
   let filter = match filter {
-    Some(f) => format!("&filter=({})", f),
+    Some(f) => {
+      let f = f.render().map_err(|e| surf::Error::from_str(400, e))?;
+      format!("&filter={}", utf8_percent_encode(&format!("({})", f), NON_ALPHANUMERIC))
+    },
     None => "".to_string(),
   };
 
-  let client = Client::new();
-  let res = client.get(format!("{}/api/collections/{}/records?perPage=100{}", *crate::PB_URL, collection, filter))
-    .header("Authorization", crate::PB_TOKEN.read().await.clone())
-    .recv_json::<RecordsResponse<T>>().await?;
+  let url = format!("api/collections/{}/records?page={}&perPage={}&sort=id{}", collection, page.max(1), per_page.clamp(1, MAX_PER_PAGE), filter);
+  let mut res = success(send(db, |token| Ok(db.client.get(&url).header("Authorization", token))).await?)?;
+  res.body_json::<Page<T>>().await.map_err(upstream)
+}
 
-  Ok(res.items)
+// None when there is no record with that id
+pub(crate) async fn get_record<T>(db: &Db, collection: &str, id: &str) -> Result<Option<T>> where T: DeserializeOwned {
+  let url = record_url(collection, id);
+  let res = send(db, |token| Ok(db.client.get(&url).header("Authorization", token))).await?;
+  if res.status() == StatusCode::NotFound {
+    return Ok(None);
+  }
+  success(res)?.body_json().await.map(Some).map_err(upstream)
 }
 
-pub(crate) async fn create_record<T>(collection: &str, new_record: T) -> Result<()> where T: Serialize {
-  let client = Client::new();
-  client.post(format!("{}/api/collections/{}/records", *crate::PB_URL, collection))
-    .header("Authorization", crate::PB_TOKEN.read().await.clone())
-    .body_json(&new_record).unwrap().await?;
+pub(crate) async fn create_record<T>(db: &Db, collection: &str, new_record: T) -> Result<()> where T: Serialize {
+  let url = format!("api/collections/{}/records", collection);
+  success(send(db, |token| db.client.post(&url).header("Authorization", token).body_json(&new_record)).await?)?;
 
   Ok(())
 }
 
-pub(crate) async fn delete_record(collection: &str, delete_record_id: String) -> Result<()> {
-  let client = Client::new();
-  client.delete(format!("{}/api/collections/{}/records/{}", *crate::PB_URL, collection, delete_record_id))
-    .header("Authorization", crate::PB_TOKEN.read().await.clone())
-    .send().await?;
+pub(crate) async fn delete_record(db: &Db, collection: &str, delete_record_id: String) -> Result<()> {
+  let url = record_url(collection, &delete_record_id);
+  let res = send(db, |token| Ok(db.client.delete(&url).header("Authorization", token))).await?;
+  // the record being gone already is what we wanted
+  if res.status() != StatusCode::NotFound {
+    success(res)?;
+  }
 
   Ok(())
 }
 
-pub(crate) async fn modify_record<T>(collection: &str, modify_record: T) -> Result<()> where T: Serialize + ModifyRecord {
-  let client = Client::new();
-  client.patch(format!("{}/api/collections/{}/records/{}", *crate::PB_URL, collection, modify_record.id()))
-    .header("Authorization", crate::PB_TOKEN.read().await.clone())
-    .body_json(&modify_record).unwrap().await?;
+pub(crate) async fn modify_record<T>(db: &Db, collection: &str, modify_record: T) -> Result<()> where T: Serialize + ModifyRecord {
+  let url = record_url(collection, modify_record.id());
+  success(send(db, |token| db.client.patch(&url).header("Authorization", token).body_json(&modify_record)).await?)?;
 
   Ok(())
 }
 
-pub(crate) async fn get_new_token() -> Result<()> {
-  let client = Client::new();
-  
-  let mut res = client.post(format!("{}/api/admins/auth-with-password", *crate::PB_URL))
+// ids can come from request bodies, encoding them keeps them from changing the path or query
+fn record_url(collection: &str, id: &str) -> String {
+  format!("api/collections/{}/records/{}", collection, utf8_percent_encode(id, NON_ALPHANUMERIC))
+}
+
+pub(crate) async fn get_new_token(db: &Db) -> Result<()> {
+  let mut res = db.client.post("api/admins/auth-with-password")
     .body_json(&TokenReq {
-      identity: (*crate::PB_EMAIL.clone()).to_string(),
-      password: (*crate::PB_PASSWORD.clone()).to_string(),
-    }).unwrap().await?;
+      identity: db.email.clone(),
+      password: db.password.clone(),
+    })?.await?;
+  if !res.status().is_success() {
+    return Err(surf::Error::from_str(res.status(), "PocketBase rejected the admin credentials"));
+  }
 
   let Token { token } = res.body_json().await?;
-  let mut new_token = crate::PB_TOKEN.write().await;
+  let mut new_token = db.token.write().await;
   *new_token = token;
   Ok(())
 }
 
+// pocketbase may not be up yet when we start, so this keeps trying instead of failing
+pub(crate) async fn connect(db: &Db) {
+  let mut delay = 1;
+  while let Err(e) = get_new_token(db).await {
+    tide::log::warn!("Failed to authenticate with PocketBase, retrying in {}s: {}", delay, e);
+    async_std::task::sleep(Duration::from_secs(delay)).await;
+    delay = (delay * 2).min(MAX_RETRY_DELAY);
+  }
+}
+
+pub(crate) async fn refresh_token(db: Arc<Db>) {
+  loop {
+    let expiry = token_expiry(&db.token.read().await);
+    let wait = match expiry {
+      Some(exp) => exp - chrono::Utc::now().timestamp() - TOKEN_REFRESH_MARGIN,
+      None => UNKNOWN_EXPIRY_REFRESH,
+    };
+    async_std::task::sleep(Duration::from_secs(wait.max(1) as u64)).await;
+    connect(&db).await;
+  }
+}
+
+// retries once with a new admin token when pocketbase rejects the current one
+async fn send<F>(db: &Db, build: F) -> Result<surf::Response> where F: Fn(&str) -> Result<RequestBuilder> {
+  let token = db.token.read().await.clone();
+  let res = build(&token)?.await.map_err(upstream)?;
+  if res.status() != StatusCode::Unauthorized {
+    return Ok(res);
+  }
+
+  renew_token(db, &token).await.map_err(upstream)?;
+  let token = db.token.read().await.clone();
+  build(&token)?.await.map_err(upstream)
+}
+
+// pocketbase accepts ids chosen by the client as long as they look like its own
+pub(crate) fn new_record_id() -> String {
+  rand::thread_rng().sample_iter(&Alphanumeric).take(15).map(|c| (c as char).to_ascii_lowercase()).collect()
+}
+
+fn success(res: surf::Response) -> Result<surf::Response> {
+  if !res.status().is_success() {
+    return Err(upstream(format!("request returned {}", res.status())));
+  }
+  Ok(res)
+}
+
+// pocketbase failures are not the client's fault, so they surface as a bad gateway
+fn upstream(e: impl Display) -> surf::Error {
+  ApiError::Upstream("pocketbase", e.to_string()).into()
+}
+
+async fn renew_token(db: &Db, rejected: &str) -> Result<()> {
+  let _renewing = db.renewing.lock().await;
+  // another request could have renewed it while we were waiting
+  if *db.token.read().await != rejected {
+    return Ok(());
+  }
+  get_new_token(db).await
+}
+
+fn token_expiry(token: &str) -> Option<i64> {
+  let Claims { exp } = decode_part(token, 1)?;
+  Some(exp)
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub(crate) struct Page<T> {
+  pub(crate) page: u32,
+  pub(crate) per_page: u32,
+  pub(crate) total_items: u32,
+  pub(crate) total_pages: u32,
+  pub(crate) items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PageQuery {
+  page: Option<u32>,
+  per_page: Option<u32>,
+}
+
+impl PageQuery {
+  // without any page parameter the whole collection is returned like before
+  pub(crate) fn page(&self) -> Option<(u32, u32)> {
+    match (self.page, self.per_page) {
+      (None, None) => None,
+      (page, per_page) => Some((page.unwrap_or(1), per_page.unwrap_or(DEFAULT_PER_PAGE))),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+  exp: i64,
+}
+
 #[derive(Deserialize)]
 struct Token {
   token: String,
@@ -75,4 +237,265 @@ struct TokenReq {
 
 pub(crate) trait ModifyRecord {
   fn id(&self) -> &String;
-}
\ No newline at end of file
+}
+
+// values are always rendered as literals, so they can't change the structure of the filter
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Filter {
+  Compare(&'static str, &'static str, Value),
+  And(Vec<Filter>),
+  Or(Vec<Filter>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+  Text(String),
+  Number(i64),
+  Bool(bool),
+}
+
+impl Filter {
+  pub(crate) fn eq(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, "=", value.into())
+  }
+
+  pub(crate) fn ne(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, "!=", value.into())
+  }
+
+  pub(crate) fn lt(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, "<", value.into())
+  }
+
+  pub(crate) fn le(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, "<=", value.into())
+  }
+
+  pub(crate) fn gt(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, ">", value.into())
+  }
+
+  pub(crate) fn ge(field: &'static str, value: impl Into<Value>) -> Self {
+    Filter::Compare(field, ">=", value.into())
+  }
+
+  // the wildcards are added here so pocketbase never interprets the text as a pattern
+  #[allow(dead_code)]
+  pub(crate) fn contains(field: &'static str, text: &str) -> Self {
+    Filter::Compare(field, "~", Value::Text(format!("%{}%", escape_like(text))))
+  }
+
+  pub(crate) fn starts_with(field: &'static str, prefix: &str) -> Self {
+    Filter::Compare(field, "~", Value::Text(format!("{}%", escape_like(prefix))))
+  }
+
+  pub(crate) fn and(self, other: Filter) -> Self {
+    match self {
+      Filter::And(mut filters) => {
+        filters.push(other);
+        Filter::And(filters)
+      },
+      filter => Filter::And(vec![filter, other]),
+    }
+  }
+
+  pub(crate) fn or(self, other: Filter) -> Self {
+    match self {
+      Filter::Or(mut filters) => {
+        filters.push(other);
+        Filter::Or(filters)
+      },
+      filter => Filter::Or(vec![filter, other]),
+    }
+  }
+
+  pub(crate) fn render(&self) -> std::result::Result<String, String> {
+    match self {
+      Filter::Compare(field, op, value) => Ok(format!("{}{}{}", field, op, value.render()?)),
+      Filter::And(filters) => Filter::render_group(filters, "&&"),
+      Filter::Or(filters) => Filter::render_group(filters, "||"),
+    }
+  }
+
+  fn render_group(filters: &[Filter], op: &str) -> std::result::Result<String, String> {
+    let parts = filters.iter().map(|f| match f {
+      Filter::Compare(..) => f.render(),
+      _ => f.render().map(|r| format!("({})", r)),
+    }).collect::<std::result::Result<Vec<String>, String>>()?;
+    Ok(parts.join(&format!(" {} ", op)))
+  }
+}
+
+impl Value {
+  fn render(&self) -> std::result::Result<String, String> {
+    match self {
+      // pocketbase only unescapes quotes, so a backslash right before the closing quote can't be expressed
+      Value::Text(text) if text.ends_with('\\') => Err(format!("filter value {:?} ends with a backslash", text)),
+      Value::Text(text) => Ok(format!("'{}'", text.replace('\'', "\\'"))),
+      Value::Number(n) => Ok(n.to_string()),
+      Value::Bool(b) => Ok(b.to_string()),
+    }
+  }
+}
+
+impl From<&str> for Value {
+  fn from(value: &str) -> Self {
+    Value::Text(value.to_string())
+  }
+}
+
+impl From<String> for Value {
+  fn from(value: String) -> Self {
+    Value::Text(value)
+  }
+}
+
+impl From<i64> for Value {
+  fn from(value: i64) -> Self {
+    Value::Number(value)
+  }
+}
+
+impl From<bool> for Value {
+  fn from(value: bool) -> Self {
+    Value::Bool(value)
+  }
+}
+
+fn escape_like(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(filter: Filter) -> String {
+    filter.render().unwrap()
+  }
+
+  #[test]
+  fn renders_comparisons() {
+    assert_eq!(render(Filter::eq("user", "abc")), "user='abc'");
+    assert_eq!(render(Filter::ne("key", "")), "key!=''");
+    assert_eq!(render(Filter::lt("timestamp", 5)), "timestamp<5");
+    assert_eq!(render(Filter::le("timestamp", -5)), "timestamp<=-5");
+    assert_eq!(render(Filter::gt("size", 0)), "size>0");
+    assert_eq!(render(Filter::ge("size", 1)), "size>=1");
+    assert_eq!(render(Filter::eq("write", true)), "write=true");
+  }
+
+  #[test]
+  fn renders_groups() {
+    let filter = Filter::eq("a", "1").and(Filter::eq("b", "2")).and(Filter::eq("c", "3"));
+    assert_eq!(render(filter), "a='1' && b='2' && c='3'");
+
+    let filter = Filter::eq("user", "u").or(Filter::eq("user", "")).and(Filter::eq("dir", "d").or(Filter::eq("dir", "")));
+    assert_eq!(render(filter), "(user='u' || user='') && (dir='d' || dir='')");
+
+    let filter = Filter::eq("a", "1").or(Filter::eq("b", "2").and(Filter::eq("c", "3")));
+    assert_eq!(render(filter), "a='1' || (b='2' && c='3')");
+  }
+
+  #[test]
+  fn escapes_quotes() {
+    assert_eq!(render(Filter::eq("path", "it's")), "path='it\\'s'");
+    assert_eq!(render(Filter::eq("path", "'")), "path='\\''");
+    assert_eq!(render(Filter::eq("path", "''")), "path='\\'\\''");
+    assert_eq!(render(Filter::eq("path", "\"quoted\"")), "path='\"quoted\"'");
+    assert_eq!(render(Filter::eq("path", "a\\'b")), "path='a\\\\'b'");
+    assert_eq!(render(Filter::eq("path", "a\\b")), "path='a\\b'");
+  }
+
+  #[test]
+  fn keeps_injections_inside_the_literal() {
+    let filter = Filter::eq("user", "x' || user!='x");
+    assert_eq!(render(filter), "user='x\\' || user!=\\'x'");
+
+    let filter = Filter::eq("path", "a') || (id!='");
+    assert_eq!(render(filter), "path='a\\') || (id!=\\''");
+
+    // every quote in the rendered value is escaped, so the literal can only end at the last quote
+    let rendered = render(Filter::eq("path", "'&&'||'~'"));
+    let inner = &rendered["path='".len()..rendered.len() - 1];
+    assert!(inner.match_indices('\'').all(|(i, _)| inner[..i].ends_with('\\')));
+  }
+
+  #[test]
+  fn rejects_trailing_backslash() {
+    assert!(Filter::eq("path", "a\\").render().is_err());
+    assert!(Filter::eq("a", "1").and(Filter::eq("path", "\\")).render().is_err());
+  }
+
+  #[test]
+  fn escapes_like_wildcards() {
+    assert_eq!(render(Filter::starts_with("path", "a/")), "path~'a/%'");
+    assert_eq!(render(Filter::starts_with("path", "100%_done/")), "path~'100\\%\\_done/%'");
+    assert_eq!(render(Filter::contains("name", "50%")), "name~'%50\\%%'");
+    assert_eq!(render(Filter::contains("name", "it's")), "name~'%it\\'s%'");
+  }
+
+  #[async_std::test]
+  async fn pages_past_records_deleted_while_reading() {
+    // pages of two, the first record is gone once the first page was served
+    let records = Arc::new(std::sync::Mutex::new(vec!["a", "b", "c", "d", "e"]));
+    let mut pb = tide::with_state(records.clone());
+    pb.at("/api/collections/:collection/records").get(|req: tide::Request<Arc<std::sync::Mutex<Vec<&'static str>>>>| async move {
+      let query: std::collections::HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+      let after = query.get("filter").map(|f| f.trim_start_matches("(id>'").trim_end_matches("')").to_string());
+      let page: usize = query["page"].parse()?;
+      let mut records = req.state().lock().unwrap();
+      let ids: Vec<_> = records.iter().filter(|id| after.as_ref().is_none_or(|a| **id > a.as_str())).collect();
+      let items: Vec<_> = ids.iter().skip((page - 1) * 2).take(2).map(|id| serde_json::json!({ "id": id })).collect();
+      let body = serde_json::json!({ "page": page, "perPage": 2, "totalItems": ids.len(), "totalPages": ids.len().div_ceil(2), "items": items });
+      records.retain(|id| *id != "a");
+      Ok(body)
+    });
+    let client: Client = surf::Config::new()
+      .set_base_url(surf::Url::parse("http://pocketbase/").unwrap())
+      .set_http_client(pb)
+      .try_into()
+      .unwrap();
+    let db = Db::new(client, &Config::default());
+
+    #[derive(Deserialize)]
+    struct Record {
+      id: String,
+    }
+    let ids: Vec<String> = get_collection_records::<Record>(&db, "test", None).await.unwrap().into_iter().map(|r| r.id).collect();
+    assert_eq!(ids, ["a", "b", "c", "d", "e"]);
+  }
+
+  #[async_std::test]
+  async fn encodes_record_ids() {
+    let paths = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut pb = tide::with_state(paths.clone());
+    pb.at("/*path").all(|req: tide::Request<Arc<std::sync::Mutex<Vec<String>>>>| async move {
+      req.state().lock().unwrap().push(req.url().path().to_string());
+      Ok(serde_json::json!({ "id": "x" }))
+    });
+    let client: Client = surf::Config::new()
+      .set_base_url(surf::Url::parse("http://pocketbase/").unwrap())
+      .set_http_client(pb)
+      .try_into()
+      .unwrap();
+    let db = Db::new(client, &Config::default());
+
+    #[derive(Serialize)]
+    struct Update {
+      id: String,
+    }
+    impl ModifyRecord for Update {
+      fn id(&self) -> &String {
+        &self.id
+      }
+    }
+    let id = "../users/x?a=b";
+    get_record::<serde_json::Value>(&db, "cloud", id).await.unwrap();
+    delete_record(&db, "cloud", id.to_string()).await.unwrap();
+    modify_record(&db, "cloud", Update { id: id.to_string() }).await.unwrap();
+    let paths = paths.lock().unwrap();
+    assert_eq!(paths.len(), 3);
+    assert!(paths.iter().all(|p| p == "/api/collections/cloud/records/%2E%2E%2Fusers%2Fx%3Fa%3Db"), "{:?}", paths);
+  }
+}
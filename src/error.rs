@@ -0,0 +1,224 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::{cloud_path::PathError, state::AppState};
+
+const REQUEST_ID: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// every failure a client can see, the middleware below turns them into the same json body
+#[derive(Clone, Debug)]
+pub(crate) enum ApiError {
+  BadRequest(String),
+  Unauthorized,
+  Forbidden,
+  NotFound,
+  Conflict(String),
+  Gone,
+  LengthRequired,
+  PayloadTooLarge,
+  Locked,
+  TooManyRequests(u64),
+  QuotaExceeded(serde_json::Value),
+  Upstream(&'static str, String),
+  Internal,
+  Status(StatusCode),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+  code: &'a str,
+  message: String,
+  request_id: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  details: Option<&'a serde_json::Value>,
+}
+
+pub(crate) struct ErrorHandler {}
+
+impl ApiError {
+  pub(crate) fn status(&self) -> StatusCode {
+    match self {
+      ApiError::BadRequest(_) => StatusCode::BadRequest,
+      ApiError::Unauthorized => StatusCode::Unauthorized,
+      ApiError::Forbidden => StatusCode::Forbidden,
+      ApiError::NotFound => StatusCode::NotFound,
+      ApiError::Conflict(_) => StatusCode::Conflict,
+      ApiError::Gone => StatusCode::Gone,
+      ApiError::LengthRequired => StatusCode::LengthRequired,
+      ApiError::PayloadTooLarge => StatusCode::PayloadTooLarge,
+      ApiError::Locked => StatusCode::Locked,
+      ApiError::TooManyRequests(_) => StatusCode::TooManyRequests,
+      ApiError::QuotaExceeded(_) => StatusCode::InsufficientStorage,
+      ApiError::Upstream(_, _) => StatusCode::BadGateway,
+      ApiError::Internal => StatusCode::InternalServerError,
+      ApiError::Status(status) => *status,
+    }
+  }
+
+  pub(crate) fn code(&self) -> &'static str {
+    match self {
+      ApiError::BadRequest(_) => "bad_request",
+      ApiError::Unauthorized => "unauthorized",
+      ApiError::Forbidden => "forbidden",
+      ApiError::NotFound => "not_found",
+      ApiError::Conflict(_) => "conflict",
+      ApiError::Gone => "gone",
+      ApiError::LengthRequired => "length_required",
+      ApiError::PayloadTooLarge => "payload_too_large",
+      ApiError::Locked => "locked",
+      ApiError::TooManyRequests(_) => "rate_limited",
+      ApiError::QuotaExceeded(_) => "quota_exceeded",
+      ApiError::Upstream(_, _) => "upstream_error",
+      ApiError::Internal => "internal_error",
+      ApiError::Status(status) if status.is_server_error() => "internal_error",
+      ApiError::Status(_) => "request_error",
+    }
+  }
+
+  // for handlers that return a response instead of an error
+  pub(crate) fn response(self) -> Response {
+    let mut res = Response::new(self.status());
+    res.set_error(self);
+    res
+  }
+
+  fn from_status(status: StatusCode) -> Self {
+    match status {
+      StatusCode::BadRequest => ApiError::BadRequest(status.canonical_reason().to_string()),
+      StatusCode::Unauthorized => ApiError::Unauthorized,
+      StatusCode::Forbidden => ApiError::Forbidden,
+      StatusCode::NotFound => ApiError::NotFound,
+      StatusCode::Conflict => ApiError::Conflict(status.canonical_reason().to_string()),
+      StatusCode::Gone => ApiError::Gone,
+      StatusCode::LengthRequired => ApiError::LengthRequired,
+      StatusCode::PayloadTooLarge => ApiError::PayloadTooLarge,
+      StatusCode::Locked => ApiError::Locked,
+      StatusCode::InternalServerError => ApiError::Internal,
+      status => ApiError::Status(status),
+    }
+  }
+
+  // errors that were passed up with `?` only keep their status unless they are one of ours
+  fn from_error(err: &tide::Error) -> Self {
+    if let Some(e) = err.downcast_ref::<ApiError>() {
+      return e.clone();
+    }
+    if let Some(e) = err.downcast_ref::<std::io::Error>() {
+      match e.kind() {
+        std::io::ErrorKind::NotFound => return ApiError::NotFound,
+        std::io::ErrorKind::PermissionDenied => return ApiError::Forbidden,
+        _ => (),
+      }
+    }
+    // body parsing errors are the client's fault and say what was wrong
+    if err.status().is_client_error() {
+      return ApiError::BadRequest(err.to_string());
+    }
+    ApiError::from_status(err.status())
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ApiError::BadRequest(message) | ApiError::Conflict(message) => f.write_str(message),
+      ApiError::Unauthorized => f.write_str("A valid token is required"),
+      ApiError::Forbidden => f.write_str("Missing permissions for this resource"),
+      ApiError::NotFound => f.write_str("Resource not found"),
+      ApiError::Gone => f.write_str("Resource no longer exists"),
+      ApiError::LengthRequired => f.write_str("The request needs a Content-Length"),
+      ApiError::PayloadTooLarge => f.write_str("The request body is too large"),
+      ApiError::Locked => f.write_str("Resource is in use by another request"),
+      ApiError::TooManyRequests(secs) => write!(f, "Too many requests, retry in {} seconds", secs),
+      ApiError::QuotaExceeded(_) => f.write_str("Storage quota exceeded"),
+      ApiError::Upstream(service, message) => write!(f, "{} request failed: {}", service, message),
+      ApiError::Internal => f.write_str("Internal server error"),
+      ApiError::Status(status) => f.write_str(status.canonical_reason()),
+    }
+  }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<PathError> for ApiError {
+  fn from(e: PathError) -> Self {
+    match e {
+      PathError::Encoding => ApiError::BadRequest("Path is not valid utf-8".to_string()),
+      PathError::Absolute => ApiError::BadRequest("Path must be relative".to_string()),
+      PathError::Escape => ApiError::BadRequest("Path leaves its directory".to_string()),
+      PathError::IllegalName => ApiError::BadRequest("Path contains an illegal name".to_string()),
+      PathError::TooLong => ApiError::BadRequest("Path is too long".to_string()),
+      PathError::Reserved => ApiError::Forbidden,
+    }
+  }
+}
+
+// wraps everything so even auth failures and `?` errors get a json body and a request id
+#[async_trait]
+impl Middleware<AppState> for ErrorHandler {
+  async fn handle(&self, mut req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+    let request_id = req.header(REQUEST_ID)
+      .map(|h| h.as_str().to_string())
+      .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+      .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    req.insert_header(REQUEST_ID, request_id.as_str());
+    let method = req.method();
+    let path = req.url().path().to_string();
+
+    let mut res = next.run(req).await;
+    let error = match res.error() {
+      Some(err) => {
+        let error = ApiError::from_error(err);
+        if error.status().is_server_error() {
+          tide::log::error!("{} {} failed ({}): {:?}", method, path, request_id, err);
+        }
+        Some(error)
+      },
+      None if (res.status().is_client_error() || res.status().is_server_error()) && res.is_empty() == Some(true) => Some(ApiError::from_status(res.status())),
+      None => None,
+    };
+
+    if let Some(error) = error {
+      let details = match &error {
+        ApiError::QuotaExceeded(details) => Some(details),
+        _ => None,
+      };
+      let body = ErrorBody { code: error.code(), message: error.to_string(), request_id: &request_id, details };
+      res.set_status(error.status());
+      res.set_body(tide::Body::from_json(&body)?);
+    }
+    res.insert_header(REQUEST_ID, request_id);
+    Ok(res)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::test_support::TestApp;
+
+  #[async_std::test]
+  async fn describes_errors_as_json() {
+    let app = TestApp::new();
+    let mut res = app.client.get("cloud/dirs/test").header("X-Request-Id", "trace-1").await.unwrap();
+    assert_eq!(res.status(), 401);
+    assert_eq!(res.header("X-Request-Id").unwrap().as_str(), "trace-1");
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "unauthorized");
+    assert_eq!(body["request_id"], "trace-1");
+
+    let mut res = app.post("bob", "cloud/files/test/..%2F..%2Fescape.txt").body("hello").await.unwrap();
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "bad_request");
+    assert_eq!(body["message"], "Path leaves its directory");
+    assert!(!body["request_id"].as_str().unwrap().is_empty());
+
+    let mut res = app.client.get("cloud/direct/not-a-link").await.unwrap();
+    assert_eq!(res.status(), 404);
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "not_found");
+  }
+}
@@ -0,0 +1,227 @@
+use std::time::{Duration, Instant};
+
+use async_std::{channel::{self, Receiver, Sender, TrySendError}, future::timeout, sync::Mutex};
+use serde::Serialize;
+use tide::Request;
+
+use crate::{cloud::{get_access_paths, path_access, token_scope}, cloud_path::CloudPath, permissions::is_admin, state::AppState};
+
+// a client that falls this far behind is dropped and has to reconnect
+const BACKLOG: usize = 100;
+// also notices closed connections, sending is the only way to find out
+const KEEPALIVE: Duration = Duration::from_secs(30);
+// changed access rules apply to open connections after at most this long
+const ACCESS_REFRESH: Duration = Duration::from_secs(30);
+
+// everyone watching a directory, events are only filtered by access when they are sent
+pub(crate) struct Events {
+  subscribers: Mutex<Vec<Subscriber>>,
+}
+
+struct Subscriber {
+  dir: CloudPath,
+  sender: Sender<CloudEvent>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CloudEvent {
+  kind: &'static str,
+  path: CloudPath,
+  dir: bool,
+  // the old path of a rename
+  from: Option<CloudPath>,
+}
+
+#[derive(Serialize)]
+struct EventData<'a> {
+  path: &'a str,
+  dir: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  from: Option<&'a str>,
+}
+
+impl CloudEvent {
+  pub(crate) fn created(path: &CloudPath, dir: bool) -> Self {
+    CloudEvent { kind: "created", path: path.clone(), dir, from: None }
+  }
+
+  pub(crate) fn updated(path: &CloudPath) -> Self {
+    CloudEvent { kind: "updated", path: path.clone(), dir: false, from: None }
+  }
+
+  pub(crate) fn deleted(path: &CloudPath, dir: bool) -> Self {
+    CloudEvent { kind: "deleted", path: path.clone(), dir, from: None }
+  }
+
+  pub(crate) fn renamed(from: &CloudPath, to: &CloudPath, dir: bool) -> Self {
+    CloudEvent { kind: "renamed", path: to.clone(), dir, from: Some(from.clone()) }
+  }
+
+  fn concerns(&self, dir: &CloudPath) -> bool {
+    within(&self.path, dir) || self.from.as_ref().is_some_and(|f| within(f, dir))
+  }
+
+  // a rename between a visible and a hidden path looks like the file appearing or disappearing
+  fn visible_part(&self, visible: impl Fn(&CloudPath) -> bool) -> Option<CloudEvent> {
+    match &self.from {
+      None => visible(&self.path).then(|| self.clone()),
+      Some(from) => match (visible(from), visible(&self.path)) {
+        (true, true) => Some(self.clone()),
+        (false, true) => Some(CloudEvent::created(&self.path, self.dir)),
+        (true, false) => Some(CloudEvent::deleted(from, self.dir)),
+        (false, false) => None,
+      },
+    }
+  }
+}
+
+impl Events {
+  pub(crate) fn new() -> Self {
+    Events { subscribers: Mutex::new(Vec::new()) }
+  }
+
+  async fn subscribe(&self, dir: CloudPath) -> Receiver<CloudEvent> {
+    let (sender, receiver) = channel::bounded(BACKLOG);
+    self.subscribers.lock().await.push(Subscriber { dir, sender });
+    receiver
+  }
+
+  pub(crate) async fn publish(&self, event: CloudEvent) {
+    self.subscribers.lock().await.retain(|s| {
+      if !event.concerns(&s.dir) {
+        return !s.sender.is_closed();
+      }
+      match s.sender.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+          s.sender.close();
+          false
+        },
+        Err(TrySendError::Closed(_)) => false,
+      }
+    });
+  }
+}
+
+pub(crate) async fn watch_dir(req: Request<AppState>) -> tide::Result {
+  let dir = match CloudPath::from_param(&req, "path") {
+    Ok(d) => d,
+    Err(e) => return Ok(e.response()),
+  };
+  // the same directories that can be listed can be watched
+  if !dir.is_root() && path_access(&get_access_paths(&req).await?, &token_scope(&req)?, is_admin(&req), &dir).is_none() {
+    return Ok(tide::Response::new(403));
+  }
+
+  // subscribed before the response goes out, so nothing after it is missed
+  let events = req.state().events.subscribe(dir).await;
+  Ok(tide::sse::upgrade(req, move |req, sender| send_events(req, sender, events.clone())))
+}
+
+async fn send_events(req: Request<AppState>, sender: tide::sse::Sender, events: Receiver<CloudEvent>) -> tide::Result<()> {
+  let is_admin = is_admin(&req);
+  let scope = token_scope(&req)?;
+  let mut access = get_access_paths(&req).await?;
+  let mut fetched = Instant::now();
+  loop {
+    let event = match timeout(KEEPALIVE, events.recv()).await {
+      Ok(Ok(event)) => event,
+      // closed by publish because the client fell behind
+      Ok(Err(_)) => return Ok(()),
+      Err(_) => {
+        if sender.send("ping", "", None).await.is_err() {
+          return Ok(());
+        }
+        continue;
+      },
+    };
+    if !is_admin && fetched.elapsed() > ACCESS_REFRESH {
+      access = get_access_paths(&req).await?;
+      fetched = Instant::now();
+    }
+
+    let event = match event.visible_part(|p| path_access(&access, &scope, is_admin, p).is_some()) {
+      Some(e) => e,
+      None => continue,
+    };
+    let data = EventData { path: &event.path, dir: event.dir, from: event.from.as_deref() };
+    if sender.send(event.kind, serde_json::to_string(&data)?, None).await.is_err() {
+      return Ok(());
+    }
+  }
+}
+
+// the root only contains itself as a path, but watching it means watching everything
+fn within(path: &CloudPath, dir: &CloudPath) -> bool {
+  dir.is_root() || path.is_within(dir)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_support::TestApp;
+
+  fn path(raw: &str) -> CloudPath {
+    CloudPath::parse(raw).unwrap()
+  }
+
+  #[async_std::test]
+  async fn delivers_events_below_the_watched_dir() {
+    let events = Events::new();
+    let docs = events.subscribe(path("docs")).await;
+    let root = events.subscribe(path("")).await;
+    events.publish(CloudEvent::created(&path("docs/a.txt"), false)).await;
+    events.publish(CloudEvent::deleted(&path("photos/b.jpg"), false)).await;
+
+    assert_eq!(docs.recv().await.unwrap().path, path("docs/a.txt"));
+    assert!(docs.try_recv().is_err());
+    assert_eq!(root.recv().await.unwrap().path, path("docs/a.txt"));
+    assert_eq!(root.recv().await.unwrap().path, path("photos/b.jpg"));
+
+    drop(docs);
+    events.publish(CloudEvent::created(&path("docs/c.txt"), false)).await;
+    assert_eq!(events.subscribers.lock().await.len(), 1);
+  }
+
+  #[test]
+  fn splits_renames_across_access() {
+    let rename = CloudEvent::renamed(&path("shared/a.txt"), &path("shared/b.txt"), false);
+    let only_old = rename.visible_part(|p| p.name() == "a.txt").unwrap();
+    assert_eq!((only_old.kind, only_old.path), ("deleted", path("shared/a.txt")));
+    let only_new = rename.visible_part(|p| p.name() == "b.txt").unwrap();
+    assert_eq!((only_new.kind, only_new.from), ("created", None));
+    assert_eq!(rename.visible_part(|_| true).unwrap().kind, "renamed");
+    assert!(rename.visible_part(|_| false).is_none());
+  }
+
+  #[async_std::test]
+  async fn notifies_watchers_of_visible_changes() {
+    use async_std::{io::{prelude::BufReadExt, BufReader}, stream::StreamExt};
+
+    let app = TestApp::new();
+    let res = app.get("bob", "cloud/events/private").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let mut res = app.get("bob", "cloud/events").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut lines = BufReader::new(res.take_body().into_reader()).lines();
+
+    let res = app.post("bob", "cloud/files/test/notes.txt").body("hi").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.post("root", "cloud/files/private/salary.txt").body("secret").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/files/test/notes.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let mut received = Vec::new();
+    while received.len() < 4 {
+      let line = async_std::future::timeout(std::time::Duration::from_secs(5), lines.next()).await.unwrap().unwrap().unwrap();
+      if !line.is_empty() {
+        received.push(line);
+      }
+    }
+    assert_eq!(received, vec![
+      "event:created", r#"data:{"path":"test/notes.txt","dir":false}"#,
+      "event:deleted", r#"data:{"path":"test/notes.txt","dir":false}"#,
+    ]);
+  }
+}
@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use tide::{Middleware, Next, Request, Route, Server};
+
+use crate::{auth::is_public, error::ApiError, permissions::{has_permissions, request_identity, Permissions}, state::AppState};
+
+// rejects requests without all of the permissions before the handler runs, admins pass every guard
+struct Guard(Permissions);
+
+// the only way routes are registered, so every route either has a guard or is declared public
+pub(crate) struct Routes {
+  app: Server<AppState>,
+  public: Vec<String>,
+}
+
+impl Routes {
+  pub(crate) fn new(app: Server<AppState>) -> Self {
+    Routes { app, public: Vec::new() }
+  }
+
+  // an empty set of permissions lets every logged in user through
+  pub(crate) fn at(&mut self, path: &str, permissions: Permissions) -> Route<'_, AppState> {
+    let mut route = self.app.at(path);
+    route.with(Guard(permissions));
+    route
+  }
+
+  pub(crate) fn public(&mut self, path: &str) -> Route<'_, AppState> {
+    self.public.push(path.to_string());
+    self.app.at(path)
+  }
+
+  // a public route the auth middleware does not let through would silently need a login, or worse, have no guard once it does
+  pub(crate) fn finish(self) -> tide::Result<Server<AppState>> {
+    let unguarded: Vec<&str> = self.public.iter().map(String::as_str).filter(|p| !is_public(&tide::http::Method::Get, p)).collect();
+    if !unguarded.is_empty() {
+      return Err(tide::Error::from_str(500, format!("Routes without a guard that are not public: {}", unguarded.join(", "))));
+    }
+    Ok(self.app)
+  }
+}
+
+#[async_trait]
+impl Middleware<AppState> for Guard {
+  async fn handle(&self, req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+    if request_identity(&req).is_none() {
+      return Ok(ApiError::Unauthorized.response());
+    }
+    if !has_permissions(&req, self.0) {
+      return Ok(tide::Response::new(403));
+    }
+    Ok(next.run(req).await)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{config::Config, storage::MemoryStorage, test_support::TestApp};
+
+  fn routes() -> Routes {
+    let config = Config::default();
+    let http = crate::http::Upstreams::new(&config).unwrap();
+    let db = crate::db::Db::new(http.pocketbase.clone(), &config);
+    let state = AppState::from_parts(config, http, db, std::sync::Arc::new(MemoryStorage::new()));
+    Routes::new(tide::with_state(state))
+  }
+
+  #[test]
+  fn rejects_public_routes_that_need_a_login() {
+    let mut ok = routes();
+    ok.at("/users", Permissions::USERS).get(|_| async { Ok("") });
+    ok.public("/cloud/direct/:uuid").get(|_| async { Ok("") });
+    assert!(ok.finish().is_ok());
+
+    let mut missing = routes();
+    missing.public("/users").get(|_| async { Ok("") });
+    let err = missing.finish().err().unwrap();
+    assert!(err.to_string().contains("/users"));
+  }
+
+  #[async_std::test]
+  async fn guards_routes_by_permission() {
+    let app = TestApp::new();
+    let res = app.get("bob", "users").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let res = app.get("bob", "iframe/portainer").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let res = app.get("bob", "cloud/usage").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.client.get("cloud/direct/00000000-0000-0000-0000-000000000000").await.unwrap();
+    assert_eq!(res.status(), 404);
+  }
+}
@@ -0,0 +1,238 @@
+use std::io::Write;
+
+use async_compression::futures::bufread::GzipDecoder;
+use async_std::{fs::File, io::{BufRead, BufReader, Read, ReadExt, WriteExt}};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::storage::{Reader, Storage};
+
+pub(crate) const BLOCK_SIZE: usize = 1 << 20;
+
+// the index is appended as gzip members with an empty payload that carry their data in the
+// FEXTRA header field, so regular gzip readers simply skip over it
+const INDEX_ID: [u8; 2] = *b"PI";
+const LOCATOR_ID: [u8; 2] = *b"PL";
+const LOCATOR_DATA_LEN: usize = 28;
+const LOCATOR_LEN: usize = 16 + LOCATOR_DATA_LEN + 10;
+const MAX_INDEX_ENTRIES: usize = (u16::MAX as usize - 4) / 8;
+
+pub(crate) type GzReader = BufReader<GzipDecoder<Reader>>;
+
+pub(crate) struct GzIndex {
+  block_size: u64,
+  pub(crate) size: u64,
+  offsets: Vec<u64>,
+}
+
+// every block is written as its own gzip member, so memory use is bounded by the block size
+// and any gzip reader that supports multiple members can still read the whole file
+pub(crate) async fn compress_to_file<R>(mut reader: R, path: &str) -> std::io::Result<u64> where R: Read + Unpin {
+  let mut file = File::create(path).await?;
+  let mut block = vec![0; BLOCK_SIZE];
+  let mut offsets = Vec::new();
+  let mut written = 0;
+  let mut total = 0;
+  loop {
+    let len = read_block(&mut reader, &mut block).await?;
+    if len == 0 && total > 0 {
+      break;
+    }
+
+    let (comp, returned) = async_std::task::spawn_blocking(move || -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+      let mut encoder = GzEncoder::new(Vec::new(), Compression::new(4));
+      encoder.write_all(&block[..len])?;
+      Ok((encoder.finish()?, block))
+    }).await?;
+    block = returned;
+
+    file.write_all(&comp).await?;
+    offsets.push(written);
+    written += comp.len() as u64;
+    total += len as u64;
+    if len < BLOCK_SIZE {
+      break;
+    }
+  }
+
+  let index_offset = written;
+  for entries in offsets.chunks(MAX_INDEX_ENTRIES) {
+    let data: Vec<u8> = entries.iter().flat_map(|o| o.to_le_bytes()).collect();
+    file.write_all(&extra_member(INDEX_ID, &data)).await?;
+  }
+
+  let mut locator = Vec::with_capacity(LOCATOR_DATA_LEN);
+  locator.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+  locator.extend_from_slice(&total.to_le_bytes());
+  locator.extend_from_slice(&index_offset.to_le_bytes());
+  locator.extend_from_slice(&(offsets.len() as u64).to_le_bytes());
+  file.write_all(&extra_member(LOCATOR_ID, &locator)).await?;
+
+  file.sync_all().await?;
+  Ok(total)
+}
+
+pub(crate) async fn decompress_file(storage: &dyn Storage, path: &str) -> std::io::Result<GzReader> {
+  let mut decoder = GzipDecoder::new(storage.read(path).await?);
+  decoder.multiple_members(true);
+  Ok(BufReader::new(decoder))
+}
+
+// files written before the index existed return None and can only be read from the start
+pub(crate) async fn read_index(storage: &dyn Storage, path: &str, len: u64) -> std::io::Result<Option<GzIndex>> {
+  if len < LOCATOR_LEN as u64 {
+    return Ok(None);
+  }
+
+  let mut locator = [0; LOCATOR_LEN];
+  storage.read_range(path, len - LOCATOR_LEN as u64, LOCATOR_LEN as u64).await?.read_exact(&mut locator).await?;
+  let data = match parse_extra_member(&locator, LOCATOR_ID) {
+    Some((data, _)) if data.len() == LOCATOR_DATA_LEN => data,
+    _ => return Ok(None),
+  };
+
+  let block_size = u32::from_le_bytes(data[0..4].try_into().unwrap()) as u64;
+  let size = u64::from_le_bytes(data[4..12].try_into().unwrap());
+  let index_offset = u64::from_le_bytes(data[12..20].try_into().unwrap());
+  let blocks = u64::from_le_bytes(data[20..28].try_into().unwrap());
+  let index_len = len - LOCATOR_LEN as u64;
+  if block_size == 0 || index_offset > index_len || blocks.saturating_mul(8) > index_len - index_offset {
+    return Ok(None);
+  }
+
+  let mut index = vec![0; (index_len - index_offset) as usize];
+  storage.read_range(path, index_offset, index_len - index_offset).await?.read_exact(&mut index).await?;
+
+  let mut offsets = Vec::with_capacity(blocks as usize + 1);
+  let mut pos = 0;
+  while pos < index.len() {
+    let (data, member_len) = match parse_extra_member(&index[pos..], INDEX_ID) {
+      Some(m) => m,
+      None => return Ok(None),
+    };
+    offsets.extend(data.chunks_exact(8).map(|o| u64::from_le_bytes(o.try_into().unwrap())));
+    pos += member_len;
+  }
+  if offsets.len() as u64 != blocks {
+    return Ok(None);
+  }
+  offsets.push(index_offset);
+
+  Ok(Some(GzIndex { block_size, size, offsets }))
+}
+
+// only the blocks overlapping the range are read and decompressed
+pub(crate) async fn decompress_range(storage: &dyn Storage, path: &str, index: &GzIndex, start: u64, len: u64) -> std::io::Result<impl BufRead + Unpin + Send + Sync + 'static> {
+  let first = (start / index.block_size) as usize;
+  let last = ((start + len - 1) / index.block_size) as usize;
+
+  let compressed = storage.read_range(path, index.offsets[first], index.offsets[last + 1] - index.offsets[first]).await?;
+  let mut decoder = GzipDecoder::new(compressed);
+  decoder.multiple_members(true);
+  let mut reader = BufReader::new(decoder);
+
+  let skip = start - first as u64 * index.block_size;
+  async_std::io::copy(&mut (&mut reader).take(skip), &mut async_std::io::sink()).await?;
+  Ok(reader.take(len))
+}
+
+fn extra_member(id: [u8; 2], data: &[u8]) -> Vec<u8> {
+  let mut member = vec![0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 255];
+  member.extend_from_slice(&(data.len() as u16 + 4).to_le_bytes());
+  member.extend_from_slice(&id);
+  member.extend_from_slice(&(data.len() as u16).to_le_bytes());
+  member.extend_from_slice(data);
+  // empty final deflate block followed by a zero crc and size
+  member.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+  member
+}
+
+fn parse_extra_member(buf: &[u8], id: [u8; 2]) -> Option<(&[u8], usize)> {
+  if buf.len() < 16 || buf[0..4] != [0x1f, 0x8b, 8, 4] || buf[12..14] != id {
+    return None;
+  }
+
+  let xlen = u16::from_le_bytes([buf[10], buf[11]]) as usize;
+  let len = u16::from_le_bytes([buf[14], buf[15]]) as usize;
+  let member_len = 12 + xlen + 10;
+  if len + 4 != xlen || buf.len() < member_len {
+    return None;
+  }
+  Some((&buf[16..16 + len], member_len))
+}
+
+async fn read_block<R>(reader: &mut R, block: &mut [u8]) -> std::io::Result<usize> where R: Read + Unpin {
+  let mut len = 0;
+  while len < block.len() {
+    let read = reader.read(&mut block[len..]).await?;
+    if read == 0 {
+      break;
+    }
+    len += read;
+  }
+  Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Read as _;
+
+  use async_std::io::Cursor;
+
+  use super::*;
+  use crate::storage::MemoryStorage;
+
+  async fn compressed(storage: &MemoryStorage, path: &str, data: &[u8]) -> u64 {
+    let local = std::env::temp_dir().join(format!("gzip-test-{}", rand::random::<u64>())).to_string_lossy().to_string();
+    compress_to_file(Cursor::new(data.to_vec()), &local).await.unwrap();
+    storage.import(path, &local).await.unwrap();
+    storage.metadata(path).await.unwrap().size
+  }
+
+  async fn read_all(mut reader: impl BufRead + Unpin) -> Vec<u8> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await.unwrap();
+    data
+  }
+
+  #[async_std::test]
+  async fn reads_ranges_through_the_index() {
+    let data: Vec<u8> = (0..2 * BLOCK_SIZE + 1234).map(|i| (i * 7 % 251) as u8).collect();
+    let storage = MemoryStorage::new();
+    let len = compressed(&storage, "a.gz", &data).await;
+
+    let index = read_index(&storage, "a.gz", len).await.unwrap().unwrap();
+    assert_eq!(index.size, data.len() as u64);
+    assert_eq!(index.offsets.len(), 4);
+    assert_eq!(read_all(decompress_file(&storage, "a.gz").await.unwrap()).await, data);
+
+    // the index members are empty, so any reader of multi-member gzip gets the plain data
+    let mut plain = Vec::new();
+    let raw = read_all(storage.read("a.gz").await.unwrap()).await;
+    flate2::read::MultiGzDecoder::new(&raw[..]).read_to_end(&mut plain).unwrap();
+    assert_eq!(plain, data);
+
+    let start = BLOCK_SIZE as u64 - 10;
+    let range = read_all(decompress_range(&storage, "a.gz", &index, start, 20).await.unwrap()).await;
+    assert_eq!(range, &data[start as usize..start as usize + 20]);
+    let tail = read_all(decompress_range(&storage, "a.gz", &index, data.len() as u64 - 5, 5).await.unwrap()).await;
+    assert_eq!(tail, &data[data.len() - 5..]);
+
+    let empty = compressed(&storage, "empty.gz", &[]).await;
+    assert_eq!(read_index(&storage, "empty.gz", empty).await.unwrap().unwrap().size, 0);
+    assert!(read_all(decompress_file(&storage, "empty.gz").await.unwrap()).await.is_empty());
+  }
+
+  #[async_std::test]
+  async fn reads_files_written_before_the_index() {
+    let data = b"written by an older version".repeat(100);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data).unwrap();
+    let legacy = encoder.finish().unwrap();
+    let storage = MemoryStorage::new();
+    storage.write("old.gz", Box::new(Cursor::new(legacy.clone())), legacy.len() as u64).await.unwrap();
+
+    assert!(read_index(&storage, "old.gz", legacy.len() as u64).await.unwrap().is_none());
+    assert!(read_index(&storage, "old.gz", 10).await.unwrap().is_none());
+    assert_eq!(read_all(decompress_file(&storage, "old.gz").await.unwrap()).await, data);
+  }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use surf::{Client, Url};
+
+use crate::config::Config;
+
+const USER_AGENT: &str = concat!("profidev-io-backend/", env!("CARGO_PKG_VERSION"));
+
+// one client per upstream, clones share the connection pool of the original
+#[derive(Clone)]
+pub(crate) struct Upstreams {
+  pub(crate) pocketbase: Client,
+  pub(crate) oidc: Client,
+  pub(crate) prometheus: Client,
+  pub(crate) nasa: Client,
+  pub(crate) s3: Client,
+}
+
+impl Upstreams {
+  pub(crate) fn new(config: &Config) -> surf::Result<Self> {
+    Ok(Upstreams {
+      pocketbase: client(&config.pocketbase.url, config.pocketbase.timeout)?,
+      oidc: client(&config.oidc.issuer, config.oidc.timeout)?,
+      prometheus: client(&config.prometheus.url, config.prometheus.timeout)?,
+      nasa: client(&config.nasa.url, config.nasa.timeout)?,
+      // requests to s3 carry the full url, the endpoint is part of what gets signed
+      s3: client("", config.s3.timeout)?,
+    })
+  }
+}
+
+// optional upstreams are left without a base url when they are not configured
+fn client(base: &str, timeout: u64) -> surf::Result<Client> {
+  let mut config = surf::Config::new();
+  if !base.is_empty() {
+    config = config.set_base_url(base_url(base)?);
+  }
+  let client = config
+    .set_timeout(Some(Duration::from_secs(timeout)))
+    .add_header("User-Agent", USER_AGENT)?
+    .add_header("Accept", "application/json")?
+    .try_into()?;
+  Ok(client)
+}
+
+// hosts are configured without a scheme in most setups and relative paths only keep the base path with a trailing slash
+fn base_url(base: &str) -> surf::Result<Url> {
+  let base = if base.contains("://") { base.to_string() } else { format!("http://{}", base) };
+  let base = if base.ends_with('/') { base } else { format!("{}/", base) };
+  Url::parse(&base).map_err(|e| surf::Error::from_str(500, format!("Invalid upstream url {}: {}", base, e)))
+}
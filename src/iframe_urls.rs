@@ -1,21 +1,13 @@
 use tide::{convert::json, Request};
 
-use crate::permissions::{has_permissions, Permissions};
+use crate::state::AppState;
 
-pub(crate) async fn get_portainer_url(req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Portainer as i32) {
-    return Ok(tide::Response::new(403));
-  }
-  
-  return_url(&crate::PORTAINER_URL)
+pub(crate) async fn get_portainer_url(req: Request<AppState>) -> tide::Result {
+  return_url(&req.state().config.iframe.portainer)
 }
 
-pub(crate) async fn get_pocketbase_url(req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Database as i32) {
-    return Ok(tide::Response::new(403));
-  }
-  
-  return_url(&crate::POCKETBASE_URL)
+pub(crate) async fn get_pocketbase_url(req: Request<AppState>) -> tide::Result {
+  return_url(&req.state().config.iframe.pocketbase)
 }
 
 fn return_url(url: &str) -> tide::Result {
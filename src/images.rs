@@ -1,26 +1,50 @@
 use serde::{Deserialize, Serialize};
 use tide::Request;
 
+use crate::{error::ApiError, state::AppState};
+
 #[derive(Deserialize, Serialize)]
 struct APODRes {
   url: String,
   media_type: String,
 }
 
-pub(crate) async fn apod(_req: Request<()>) -> tide::Result {
-  let url = format!("https://api.nasa.gov/planetary/apod?api_key={}", *crate::NASA_API_KEY);
-  let mut res = surf::get(url).await?;
-  let apod: APODRes = res.body_json().await?;
+pub(crate) async fn apod(req: Request<AppState>) -> tide::Result {
+  let apod = get_apod(req.state(), &req.state().config.nasa.api_key).await?;
   let res = tide::Response::builder(200).body(tide::Body::from_json(&apod)?);
   Ok(res.build())
 }
 
-pub(crate) async fn apod_direct(req: Request<()>) -> tide::Result {
-  let token = req.param("token").unwrap_or_default().to_string();
-
-  let url = format!("https://api.nasa.gov/planetary/apod?api_key={}", token);
-  let mut res = surf::get(url).await?;
-  let apod: APODRes = res.body_json().await?;
+pub(crate) async fn apod_direct(req: Request<AppState>) -> tide::Result {
+  let token = req.param("token").unwrap_or_default();
+  let apod = get_apod(req.state(), token).await?;
   let res = tide::Response::builder(200).body(tide::Body::from_json(&apod)?);
   Ok(res.build())
 }
+
+// nasa failing is not the client's fault, so it surfaces as a bad gateway
+async fn get_apod(state: &AppState, api_key: &str) -> Result<APODRes, ApiError> {
+  let url = format!("planetary/apod?api_key={}", api_key);
+  let mut res = state.http.nasa.get(url).await.map_err(|e| ApiError::Upstream("nasa", e.to_string()))?;
+  if !res.status().is_success() {
+    return Err(ApiError::Upstream("nasa", format!("request returned {}", res.status())));
+  }
+  res.body_json().await.map_err(|e| ApiError::Upstream("nasa", e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::test_support::TestApp;
+
+  #[async_std::test]
+  async fn reports_nasa_failures_as_upstream_errors() {
+    let app = TestApp::new();
+    let mut res = app.get("bob", "images/apod").await.unwrap();
+    assert_eq!(res.status(), 502);
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "upstream_error");
+    assert!(body["message"].as_str().unwrap().starts_with("nasa request failed"));
+    let res = app.client.get("images/apod/direct/key").await.unwrap();
+    assert_eq!(res.status(), 502);
+  }
+}
@@ -1,82 +1,131 @@
 use std::str::FromStr;
 
-use async_std::sync::RwLock;
 use surf::http::headers::HeaderValue;
 use tide::{log::LevelFilter, security::{CorsMiddleware, Origin}};
 
-use crate::auth::TokenAuth;
+use crate::{auth::TokenAuth, config::{Args, Config}, error::ErrorHandler, guards::Routes, permissions::Permissions, rate_limit::{IpRateLimit, UserRateLimit}, state::AppState};
 
+mod api_keys;
+mod audit;
 mod auth;
 mod metrics;
+mod oidc;
 mod permissions;
 mod images;
 mod users;
 mod iframe_urls;
 mod cloud;
+mod cloud_path;
+mod config;
 mod db;
-
-lazy_static::lazy_static! {
-    static ref METRICS_HOST: String = std::env::var("METRICS_HOST").unwrap_or("localhost".to_string());
-    static ref NASA_API_KEY: String = std::env::var("NASA_API_KEY").unwrap_or("DEMO_KEY".to_string());
-
-    static ref PORTAINER_URL: String = std::env::var("PORTAINER_URL").unwrap_or("".to_string());
-    static ref POCKETBASE_URL: String = std::env::var("POCKETBASE_URL").unwrap_or("".to_string());
-
-    static ref PB_TOKEN: RwLock<String> = RwLock::new("".to_string());
-    static ref PB_EMAIL: String = std::env::var("PB_EMAIL").unwrap_or("".to_string());
-    static ref PB_PASSWORD: String = std::env::var("PB_PASSWORD").unwrap_or("".to_string());
-    static ref PB_URL: String = std::env::var("PB_URL").unwrap_or("localhost:8090".to_string());
-
-    static ref CLOUD_DIR: String = std::env::var("CLOUD_DIR").unwrap_or("cloud".to_string());
-    static ref CLOUD_URL: String = std::env::var("CLOUD_URL").unwrap_or("https://api.profidev.io/cloud/direct".to_string());
-}
+mod error;
+mod events;
+mod guards;
+mod gzip;
+mod http;
+mod range;
+mod rate_limit;
+mod state;
+mod storage;
+#[cfg(test)]
+mod test_support;
+mod quota;
+mod trash;
+mod uploads;
+mod versions;
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     dotenv::dotenv().ok();
-    
+
+    let config = match Args::parse(std::env::args().skip(1)).and_then(|args| Ok((Config::load(&args)?, args.print_config))) {
+        Ok((config, true)) => {
+            println!("{}", toml::to_string(&config.redacted())?);
+            return Ok(());
+        },
+        Ok((config, false)) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(2);
+        },
+    };
+
     let log_level = std::env::var("RUST_LOG_LEVEL").unwrap_or("info".to_string());
     tide::log::with_level(LevelFilter::from_str(&log_level).unwrap_or(LevelFilter::Info));
 
-    db::get_new_token().await?;
+    let listen = config.listen.clone();
+    let state = AppState::new(config)?;
+    db::connect(&state.db).await;
+    async_std::task::spawn(db::refresh_token(state.db.clone()));
+    quota::load_usage(&state).await?;
+    uploads::load_reservations(&state).await?;
+    async_std::task::spawn(versions::prune_expired(state.clone()));
+    async_std::task::spawn(trash::purge_expired(state.clone()));
+    async_std::task::spawn(uploads::purge_abandoned(state.clone()));
 
+    app(state)?.listen(listen).await?;
+    Ok(())
+}
+
+fn app(state: AppState) -> tide::Result<tide::Server<AppState>> {
     let cors = CorsMiddleware::new()
-        .allow_origin(Origin::from("*"))
+        .allow_origin(Origin::from(state.config.cors_origins.clone()))
         .allow_methods("GET, POST, OPTIONS, PUT, DELETE, PATCH".parse::<HeaderValue>().unwrap());
 
-    let mut app = tide::new();
+    let mut app = tide::with_state(state);
 
-    app.with(cors).with(TokenAuth{});
-    app.at("/metrics").post(metrics::metrics);
-    app.at("/users").get(users::get_users);
-    app.at("/users").post(users::create_user);
-    app.at("/users").delete(users::delete_user);
-    app.at("/users").patch(users::update_user);
-    app.at("/images/apod").get(images::apod);
-    app.at("/images/apod/direct/:token").get(images::apod_direct);
-    app.at("/iframe/portainer").get(iframe_urls::get_portainer_url);
-    app.at("/iframe/pocketbase").get(iframe_urls::get_pocketbase_url);
-    app.at("/cloud/access").get(cloud::get_access);
-    app.at("/cloud/access").post(cloud::create_access);
-    app.at("/cloud/access").delete(cloud::delete_access);
-    app.at("/cloud/access").patch(cloud::update_access);
-    app.at("/cloud/dirs").get(cloud::get_dir_files);
-    app.at("/cloud/dirs").put(cloud::download_multiple);
-    app.at("/cloud/dirs/*path").get(cloud::get_dir_files);
-    app.at("/cloud/dirs/*path").post(cloud::create_dir);
-    app.at("/cloud/dirs/*path").delete(cloud::delete_dir);
-    app.at("/cloud/dirs/*path").patch(cloud::rename_dir);
-    app.at("/cloud/dirs/*path").put(cloud::download_multiple);
-    app.at("/cloud/files/*path").post(cloud::upload_file);
-    app.at("/cloud/files/*path").get(cloud::download_file);
-    app.at("/cloud/files/*path").delete(cloud::delete_file);
-    app.at("/cloud/files/*path").patch(cloud::rename_file);
-    app.at("/cloud/check/*path").get(cloud::check_if_exists);
-    app.at("/cloud/check_multiple").post(cloud::check_if_exists_multiple);
-    app.at("/cloud/check_multiple/*path").post(cloud::check_if_exists_multiple);
-    app.at("/cloud/direct/*path").post(cloud::create_direct_link);
-    app.at("/cloud/direct/:uuid").get(cloud::get_direct_link);
+    app.with(cors).with(ErrorHandler{}).with(IpRateLimit{}).with(TokenAuth{}).with(UserRateLimit{});
+    let mut routes = Routes::new(app);
+    routes.at("/metrics", Permissions::METRICS).post(metrics::metrics);
+    routes.at("/users", Permissions::USERS).get(users::get_users);
+    routes.at("/users", Permissions::USERS).post(users::create_user);
+    routes.at("/users", Permissions::USERS).delete(users::delete_user);
+    routes.at("/users", Permissions::USERS).patch(users::update_user);
+    routes.at("/permissions", Permissions::empty()).get(permissions::get_permissions);
+    routes.at("/audit", Permissions::ADMIN).get(audit::get_audit);
+    routes.at("/keys", Permissions::empty()).get(api_keys::get_api_keys);
+    routes.at("/keys", Permissions::empty()).post(api_keys::create_api_key);
+    routes.at("/keys/:id", Permissions::empty()).delete(api_keys::delete_api_key);
+    routes.at("/images/apod", Permissions::empty()).get(images::apod);
+    routes.public("/images/apod/direct/:token").get(images::apod_direct);
+    routes.at("/iframe/portainer", Permissions::PORTAINER).get(iframe_urls::get_portainer_url);
+    routes.at("/iframe/pocketbase", Permissions::DATABASE).get(iframe_urls::get_pocketbase_url);
+    routes.at("/cloud/access", Permissions::CLOUD_MANAGE).get(cloud::get_access);
+    routes.at("/cloud/access", Permissions::CLOUD_MANAGE).post(cloud::create_access);
+    routes.at("/cloud/access", Permissions::CLOUD_MANAGE).delete(cloud::delete_access);
+    routes.at("/cloud/access", Permissions::CLOUD_MANAGE).patch(cloud::update_access);
+    routes.at("/cloud/quotas", Permissions::CLOUD_MANAGE).get(quota::get_quotas);
+    routes.at("/cloud/quotas", Permissions::CLOUD_MANAGE).post(quota::create_quota);
+    routes.at("/cloud/quotas", Permissions::CLOUD_MANAGE).delete(quota::delete_quota);
+    routes.at("/cloud/quotas", Permissions::CLOUD_MANAGE).patch(quota::update_quota);
+    routes.at("/cloud/usage", Permissions::CLOUD).get(quota::get_usage);
+    routes.at("/cloud/dirs", Permissions::CLOUD).get(cloud::get_dir_files);
+    routes.at("/cloud/dirs", Permissions::CLOUD).put(cloud::download_multiple);
+    routes.at("/cloud/dirs/*path", Permissions::CLOUD).get(cloud::get_dir_files);
+    routes.at("/cloud/dirs/*path", Permissions::CLOUD).post(cloud::create_dir);
+    routes.at("/cloud/dirs/*path", Permissions::CLOUD).delete(cloud::delete_dir);
+    routes.at("/cloud/dirs/*path", Permissions::CLOUD).patch(cloud::rename_dir);
+    routes.at("/cloud/dirs/*path", Permissions::CLOUD).put(cloud::download_multiple);
+    routes.at("/cloud/files/*path", Permissions::CLOUD).post(cloud::upload_file);
+    routes.at("/cloud/files/*path", Permissions::CLOUD).get(cloud::download_file);
+    routes.at("/cloud/files/*path", Permissions::CLOUD).delete(cloud::delete_file);
+    routes.at("/cloud/files/*path", Permissions::CLOUD).patch(cloud::rename_file);
+    routes.at("/cloud/check/*path", Permissions::CLOUD).get(cloud::check_if_exists);
+    routes.at("/cloud/check_multiple", Permissions::CLOUD).post(cloud::check_if_exists_multiple);
+    routes.at("/cloud/check_multiple/*path", Permissions::CLOUD).post(cloud::check_if_exists_multiple);
+    routes.at("/cloud/direct/*path", Permissions::CLOUD).post(cloud::create_direct_link);
+    routes.public("/cloud/direct/:uuid").get(cloud::get_direct_link);
+    routes.at("/cloud/events", Permissions::CLOUD).get(events::watch_dir);
+    routes.at("/cloud/events/*path", Permissions::CLOUD).get(events::watch_dir);
+    routes.at("/cloud/versions/*path", Permissions::CLOUD).get(versions::get_versions);
+    routes.at("/cloud/versions/*path", Permissions::CLOUD).post(versions::restore_version);
+    routes.at("/cloud/trash", Permissions::CLOUD).get(trash::get_trash);
+    routes.at("/cloud/trash", Permissions::CLOUD).post(trash::restore_trash);
+    routes.at("/cloud/trash", Permissions::CLOUD).delete(trash::purge_trash);
+    routes.at("/cloud/uploads", Permissions::CLOUD).post(uploads::create_upload);
+    routes.at("/cloud/uploads/:id", Permissions::CLOUD).get(uploads::get_upload);
+    routes.at("/cloud/uploads/:id", Permissions::CLOUD).patch(uploads::append_upload);
+    routes.at("/cloud/uploads/:id", Permissions::CLOUD).delete(uploads::delete_upload);
 
-    app.listen("0.0.0.0:8080").await?;
-    Ok(())
+    routes.finish()
 }
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 use surf::Client;
 use tide::{Request, Response};
 
-use crate::permissions::{has_permissions, Permissions};
+use crate::{error::ApiError, state::AppState};
 
 #[derive(Deserialize, Debug)]
 struct MetricsReq {
@@ -59,23 +59,21 @@ struct MetricsRes {
   data: HashMap<String, Vec<(i64, f32)>>,
 }
 
-pub(crate) async fn metrics(mut req: Request<()>) -> tide::Result {
-    if !has_permissions(&req, Permissions::Metrics as i32) {
-        return Ok(tide::Response::new(403));
-    }
-
+pub(crate) async fn metrics(mut req: Request<AppState>) -> tide::Result {
     let MetricsReq { start, end, step, metrics } = req.body_json().await?;
+    let prometheus = &req.state().http.prometheus;
     if start > end || step < 1 {
       return Ok(tide::Response::new(400));
     }
 
     let body = match metrics {
       MetricsType::Cpu => {
-        let metrics = get_metrics::<Metrics<CPUInfo>>("sum by (cpu) (irate(node_cpu_seconds_total{job=\"node\", mode!=\"idle\"}[30s])) * 100", start, end, step).await?;
+        let metrics = get_metrics::<Metrics<CPUInfo>>(prometheus, "sum by (cpu) (irate(node_cpu_seconds_total{job=\"node\", mode!=\"idle\"}[30s])) * 100", start, end, step).await?;
         
         let mut cores: HashMap<String, Vec<(i64, f32)>> = HashMap::new();
         for core in metrics.data.result {
-          cores.insert(core.metric.cpu, core.values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
+          let values = values(&core)?;
+          cores.insert(core.metric.cpu, values);
         }
         
         let mut total: Vec<(i64, f32)> = Vec::new();
@@ -96,30 +94,30 @@ pub(crate) async fn metrics(mut req: Request<()>) -> tide::Result {
         tide::Body::from_json(&res_body)?
       },
       MetricsType::Memory => {
-        let metrics = get_metrics::<Metrics<Empty>>("100 * (1 - (node_memory_MemFree_bytes + node_memory_Cached_bytes) / node_memory_MemTotal_bytes)", start, end, step).await?;
+        let metrics = get_metrics::<Metrics<Empty>>(prometheus, "100 * (1 - (node_memory_MemFree_bytes + node_memory_Cached_bytes) / node_memory_MemTotal_bytes)", start, end, step).await?;
 
         let mut data: HashMap<String, Vec<(i64, f32)>> = HashMap::new();
-        data.insert("memory".to_string(), metrics.data.result[0].values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
+        data.insert("memory".to_string(), first_values(&metrics)?);
 
         tide::Body::from_json(&MetricsRes { data })?
       },
       MetricsType::Network => {
-        let incoming = get_metrics::<Metrics<Empty>>("irate(node_network_receive_bytes_total{device=\"eth0\"}[30s])", start, end, step).await?;
-        let outgoing = get_metrics::<Metrics<Empty>>("irate(node_network_transmit_bytes_total{device=\"eth0\"}[30s])", start, end, step).await?;
+        let incoming = get_metrics::<Metrics<Empty>>(prometheus, "irate(node_network_receive_bytes_total{device=\"eth0\"}[30s])", start, end, step).await?;
+        let outgoing = get_metrics::<Metrics<Empty>>(prometheus, "irate(node_network_transmit_bytes_total{device=\"eth0\"}[30s])", start, end, step).await?;
 
         let mut data: HashMap<String, Vec<(i64, f32)>> = HashMap::new();
-        data.insert("incoming".to_string(), incoming.data.result[0].values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
-        data.insert("outgoing".to_string(), outgoing.data.result[0].values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
+        data.insert("incoming".to_string(), first_values(&incoming)?);
+        data.insert("outgoing".to_string(), first_values(&outgoing)?);
 
         tide::Body::from_json(&MetricsRes { data })?
       },
       MetricsType::Disk => {
-        let free = get_metrics::<Metrics<Empty>>("node_filesystem_avail_bytes{mountpoint=\"/\"}", start, end, step).await?;
-        let total = get_metrics::<Metrics<Empty>>("node_filesystem_size_bytes{mountpoint=\"/\"}", start, end, step).await?;
+        let free = get_metrics::<Metrics<Empty>>(prometheus, "node_filesystem_avail_bytes{mountpoint=\"/\"}", start, end, step).await?;
+        let total = get_metrics::<Metrics<Empty>>(prometheus, "node_filesystem_size_bytes{mountpoint=\"/\"}", start, end, step).await?;
 
         let mut data: HashMap<String, Vec<(i64, f32)>> = HashMap::new();
-        data.insert("free".to_string(), free.data.result[0].values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
-        data.insert("total".to_string(), total.data.result[0].values.iter().map(|d| (d.time, d.value.parse::<f32>().unwrap())).collect());
+        data.insert("free".to_string(), first_values(&free)?);
+        data.insert("total".to_string(), first_values(&total)?);
 
         tide::Body::from_json(&MetricsRes { data })?
       },
@@ -130,16 +128,31 @@ pub(crate) async fn metrics(mut req: Request<()>) -> tide::Result {
     Ok(res)
 }
 
-async fn get_metrics<T>(query: &str, start: i64, end: i64, step: i32) -> surf::Result<T> 
+async fn get_metrics<T>(prometheus: &Client, query: &str, start: i64, end: i64, step: i32) -> surf::Result<T> 
 where T: for<'de> Deserialize<'de> {
-  let client = Client::new();
   let start = Utc.timestamp_millis_opt(start).single().unwrap_or_default().with_second(0).unwrap_or_default().format("%Y-%m-%dT%H:%M:%SZ").to_string();
   let end = Utc.timestamp_millis_opt(end).single().unwrap_or_default().with_second(0).unwrap_or_default().format("%Y-%m-%dT%H:%M:%SZ").to_string();
   
   let encoded_query = utf8_percent_encode(query, NON_ALPHANUMERIC).to_string();
-  let url = format!("{}:9090/api/v1/query_range?query={}&start={}&end={}&step={}m", *crate::METRICS_HOST, encoded_query, start, end, step);
+  let url = format!("api/v1/query_range?query={}&start={}&end={}&step={}m", encoded_query, start, end, step);
   
-  let mut res = client.get(url).await?;
-  
-  res.body_json().await
+  let mut res = prometheus.get(url).await.map_err(|e| ApiError::Upstream("prometheus", e.to_string()))?;
+  if !res.status().is_success() {
+    return Err(ApiError::Upstream("prometheus", format!("query returned {}", res.status())).into());
+  }
+  Ok(res.body_json().await.map_err(|e| ApiError::Upstream("prometheus", e.to_string()))?)
+}
+
+// a query without any matching series just has no values
+fn first_values<T>(metrics: &Metrics<T>) -> Result<Vec<(i64, f32)>, ApiError> {
+  match metrics.data.result.first() {
+    Some(table) => values(table),
+    None => Ok(Vec::new()),
+  }
+}
+
+fn values<T>(table: &MetricsTable<T>) -> Result<Vec<(i64, f32)>, ApiError> {
+  table.values.iter()
+    .map(|d| d.value.parse::<f32>().map(|v| (d.time, v)).map_err(|_| ApiError::Upstream("prometheus", format!("invalid sample value {:?}", d.value))))
+    .collect()
 }
\ No newline at end of file
@@ -0,0 +1,234 @@
+use async_std::sync::{Mutex, RwLock};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{auth::{decode_part, Identity}, config::{Config, OidcConfig}, db::{create_record, get_collection_records, new_record_id, Filter}, error::ApiError, permissions::Permissions, state::AppState};
+
+// an unknown key id makes us fetch the keys again, but not more often than this
+const MIN_REFETCH: i64 = 60;
+const ALGORITHMS: [Algorithm; 8] = [Algorithm::RS256, Algorithm::RS384, Algorithm::RS512, Algorithm::PS256, Algorithm::PS384, Algorithm::PS512, Algorithm::ES256, Algorithm::ES384];
+
+// the signing keys of the issuer, fetched lazily and refreshed when they get old or rotate
+pub(crate) struct Oidc {
+  config: OidcConfig,
+  keys: RwLock<Option<Keys>>,
+  fetching: Mutex<()>,
+}
+
+struct Keys {
+  set: JwkSet,
+  fetched: i64,
+}
+
+#[derive(Deserialize)]
+struct Issuer {
+  iss: String,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+  jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct User {
+  id: String,
+  permissions: i32,
+}
+
+#[derive(Serialize)]
+struct UserCreate {
+  id: String,
+  email: String,
+  name: String,
+  username: String,
+  permissions: i32,
+  password: String,
+  #[serde(rename = "passwordConfirm")]
+  password_confirm: String,
+  verified: bool,
+}
+
+impl Oidc {
+  pub(crate) fn new(config: &Config) -> Self {
+    Oidc { config: config.oidc.clone(), keys: RwLock::new(None), fetching: Mutex::new(()) }
+  }
+
+  // only tokens from the configured issuer are ours, everything else goes to pocketbase
+  pub(crate) fn handles(&self, token: &str) -> bool {
+    !self.config.issuer.is_empty() && decode_part::<Issuer>(token, 1).is_some_and(|c| c.iss == self.config.issuer)
+  }
+
+  async fn key(&self, state: &AppState, kid: Option<&str>) -> tide::Result<Option<DecodingKey>> {
+    let now = chrono::Utc::now().timestamp();
+    let fetched = match &*self.keys.read().await {
+      Some(keys) => {
+        if now - keys.fetched < self.config.jwks_refresh as i64 {
+          if let Some(key) = find_key(&keys.set, kid) {
+            return Ok(Some(key));
+          }
+        }
+        keys.fetched
+      },
+      None => 0,
+    };
+    if now - fetched < MIN_REFETCH {
+      return Ok(None);
+    }
+
+    let _fetching = self.fetching.lock().await;
+    // another request could have fetched them while we were waiting
+    if self.keys.read().await.as_ref().is_some_and(|k| k.fetched > fetched) {
+      return Ok(self.keys.read().await.as_ref().and_then(|k| find_key(&k.set, kid)));
+    }
+    let set = match self.fetch_keys(state).await {
+      Ok(set) => set,
+      // old keys are better than locking everyone out while the issuer is down
+      Err(e) => match self.keys.read().await.as_ref().and_then(|k| find_key(&k.set, kid)) {
+        Some(key) => {
+          tide::log::warn!("Failed to refresh the oidc signing keys, using the old ones: {}", e);
+          return Ok(Some(key));
+        },
+        None => return Err(e.into()),
+      },
+    };
+    let key = find_key(&set, kid);
+    *self.keys.write().await = Some(Keys { set, fetched: now });
+    Ok(key)
+  }
+
+  async fn fetch_keys(&self, state: &AppState) -> Result<JwkSet, ApiError> {
+    let client = &state.http.oidc;
+    let upstream = |e: surf::Error| ApiError::Upstream("oidc", e.to_string());
+    let jwks_url = if self.config.jwks_url.is_empty() {
+      let Discovery { jwks_uri } = client.get(".well-known/openid-configuration").recv_json().await.map_err(upstream)?;
+      jwks_uri
+    } else {
+      self.config.jwks_url.clone()
+    };
+    client.get(jwks_url).recv_json().await.map_err(upstream)
+  }
+}
+
+// None when the token is not valid, signature, issuer, audience and expiry are all checked
+pub(crate) async fn identify(state: &AppState, token: &str) -> tide::Result<Option<Identity>> {
+  let oidc = &state.oidc;
+  let header = match jsonwebtoken::decode_header(token) {
+    Ok(h) if ALGORITHMS.contains(&h.alg) => h,
+    _ => return Ok(None),
+  };
+  let key = match oidc.key(state, header.kid.as_deref()).await? {
+    Some(k) => k,
+    None => return Ok(None),
+  };
+
+  let mut validation = Validation::new(header.alg);
+  validation.set_issuer(&[&oidc.config.issuer]);
+  validation.set_audience(&[&oidc.config.audience]);
+  let claims = match jsonwebtoken::decode::<Value>(token, &key, &validation) {
+    Ok(data) => data.claims,
+    Err(_) => return Ok(None),
+  };
+
+  // the email is how external accounts are matched, so it has to be one the issuer checked
+  let email = match claims.get("email").and_then(Value::as_str) {
+    Some(email) if claims.get("email_verified") != Some(&Value::Bool(false)) => email,
+    _ => return Ok(None),
+  };
+  let user = match get_collection_records::<User>(&state.db, "users", Some(Filter::eq("email", email))).await?.pop() {
+    Some(user) => user,
+    None if oidc.config.create_users => create_user(state, email, &claims).await?,
+    None => return Ok(None),
+  };
+
+  let granted = oidc.config.rules.iter()
+    .filter(|r| claim_matches(&claims, &r.claim, &r.value))
+    .fold(0, |bits, r| bits | r.permissions);
+  let exp = claims.get("exp").and_then(Value::as_i64).unwrap_or_default();
+  Ok(Some(Identity::new(user.id, Permissions::from_i32(user.permissions | granted), exp)))
+}
+
+// new accounts start without permissions of their own, the rules grant them on every login
+async fn create_user(state: &AppState, email: &str, claims: &Value) -> tide::Result<User> {
+  let id = new_record_id();
+  let preferred = claims.get("preferred_username").and_then(Value::as_str).unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+  let username: String = preferred.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '.' || *c == '-').collect();
+  let password = hex::encode(rand::random::<[u8; 32]>());
+  let user = UserCreate {
+    id: id.clone(),
+    email: email.to_string(),
+    name: claims.get("name").and_then(Value::as_str).unwrap_or(preferred).to_string(),
+    // pocketbase usernames are unique, the id suffix keeps logins with the same name apart
+    username: format!("{}_{}", username, &id[..5]),
+    permissions: 0,
+    password: password.clone(),
+    password_confirm: password,
+    verified: true,
+  };
+  create_record(&state.db, "users", user).await?;
+  Ok(User { id, permissions: 0 })
+}
+
+fn find_key(set: &JwkSet, kid: Option<&str>) -> Option<DecodingKey> {
+  let jwk = match kid {
+    Some(kid) => set.find(kid)?,
+    // without a key id only an issuer with a single key is unambiguous
+    None if set.keys.len() == 1 => &set.keys[0],
+    None => return None,
+  };
+  DecodingKey::from_jwk(jwk).ok()
+}
+
+fn claim_matches(claims: &Value, path: &str, expected: &str) -> bool {
+  let claim = path.split('.').try_fold(claims, |value, name| value.get(name));
+  match claim {
+    Some(Value::String(s)) => s == expected,
+    Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(expected)),
+    Some(Value::Bool(b)) => b.to_string() == expected,
+    Some(Value::Number(n)) => n.to_string() == expected,
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::{config::{Config, OidcRule}, test_support::{issuer_token, TestApp}};
+
+  #[test]
+  fn matches_nested_claims() {
+    let claims = json!({ "realm_access": { "roles": ["cloud", "metrics"] }, "group": "admins", "level": 3 });
+    assert!(claim_matches(&claims, "realm_access.roles", "metrics"));
+    assert!(!claim_matches(&claims, "realm_access.roles", "admin"));
+    assert!(claim_matches(&claims, "group", "admins"));
+    assert!(claim_matches(&claims, "level", "3"));
+    assert!(!claim_matches(&claims, "missing.claim", "x"));
+  }
+
+  #[async_std::test]
+  async fn maps_oidc_logins_onto_users() {
+    let mut config = Config::default();
+    config.oidc.issuer = "http://issuer".to_string();
+    config.oidc.audience = "backend".to_string();
+    config.oidc.create_users = true;
+    config.oidc.rules = vec![OidcRule { claim: "realm_access.roles".to_string(), value: "cloud".to_string(), permissions: 8 }];
+    let app = TestApp::with(config);
+
+    let claims = json!({ "iss": "http://issuer", "aud": "backend", "exp": 4102444800i64, "email": "eve@example.com", "realm_access": { "roles": ["cloud"] } });
+    let res = app.client.get("cloud/usage").header("Authorization", format!("Bearer {}", issuer_token(claims.clone()))).await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let mut other = claims.clone();
+    other["realm_access"]["roles"] = json!([]);
+    let res = app.client.get("cloud/usage").header("Authorization", format!("Bearer {}", issuer_token(other))).await.unwrap();
+    assert_eq!(res.status(), 403);
+
+    let mut foreign = claims;
+    foreign["aud"] = json!("someone-else");
+    let res = app.client.get("cloud/usage").header("Authorization", format!("Bearer {}", issuer_token(foreign))).await.unwrap();
+    assert_eq!(res.status(), 401);
+  }
+}
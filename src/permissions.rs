@@ -1,21 +1,134 @@
+use bitflags::bitflags;
+use serde::Serialize;
 use tide::Request;
 
-pub(crate) enum Permissions {
-  Admin = 1,
-  Users = 2,
-  Metrics = 4,
-  Cloud = 8,
-  Portainer = 16,
-  Database = 32,
-  CloudManage = 64,
+use crate::{auth::Identity, error::ApiError, state::AppState};
+
+bitflags! {
+  // stored as the plain bits in pocketbase, so existing values keep their meaning
+  #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+  pub(crate) struct Permissions: i32 {
+    const ADMIN = 1;
+    const USERS = 2;
+    const METRICS = 4;
+    const CLOUD = 8;
+    const PORTAINER = 16;
+    const DATABASE = 32;
+    const CLOUD_MANAGE = 64;
+  }
+}
+
+// named sets of permissions, only expanded when users or keys are saved
+pub(crate) const ROLES: [(&str, Permissions); 6] = [
+  ("viewer", Permissions::METRICS),
+  ("cloud-editor", Permissions::CLOUD),
+  ("cloud-manager", Permissions::CLOUD.union(Permissions::CLOUD_MANAGE)),
+  ("operator", Permissions::METRICS.union(Permissions::PORTAINER).union(Permissions::DATABASE)),
+  ("user-manager", Permissions::USERS),
+  ("admin", Permissions::ADMIN),
+];
+
+#[derive(Serialize)]
+struct Flag {
+  name: String,
+  bits: i32,
+}
+
+#[derive(Serialize)]
+struct Role {
+  name: &'static str,
+  bits: i32,
+  flags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PermissionList {
+  flags: Vec<Flag>,
+  roles: Vec<Role>,
+}
+
+impl Permissions {
+  // unknown bits are dropped, they grant nothing
+  pub(crate) fn from_i32(bits: i32) -> Self {
+    Permissions::from_bits_truncate(bits)
+  }
+
+  pub(crate) fn names(self) -> Vec<String> {
+    self.iter_names().map(|(name, _)| name.to_lowercase()).collect()
+  }
+
+  pub(crate) fn from_roles(roles: &[String]) -> Result<Self, ApiError> {
+    roles.iter().try_fold(Permissions::empty(), |permissions, role| {
+      match ROLES.iter().find(|(name, _)| name == role) {
+        Some((_, p)) => Ok(permissions | *p),
+        None => Err(ApiError::BadRequest(format!("unknown role {}", role))),
+      }
+    })
+  }
+}
+
+pub(crate) async fn get_permissions(_req: Request<AppState>) -> tide::Result {
+  let list = PermissionList {
+    flags: Permissions::all().iter_names().map(|(name, p)| Flag { name: name.to_lowercase(), bits: p.bits() }).collect(),
+    roles: ROLES.iter().map(|(name, p)| Role { name, bits: p.bits(), flags: p.names() }).collect(),
+  };
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&list)?).build())
 }
 
-pub(crate) fn has_permissions(req: &Request<()> , permissions: i32) -> bool {
-  let req_permissions: i32 = req.header("Permissions").unwrap().as_str().parse().unwrap();
-  req_permissions & permissions == permissions || (req_permissions & Permissions::Admin as i32) == Permissions::Admin as i32
+pub(crate) fn has_permissions(req: &Request<AppState>, permissions: Permissions) -> bool {
+  let req_permissions = request_permissions(req);
+  req_permissions.contains(permissions) || req_permissions.contains(Permissions::ADMIN)
 }
 
-pub(crate) fn is_admin(req: &Request<()>) -> bool {
-  let req_permissions: i32 = req.header("Permissions").unwrap().as_str().parse().unwrap();
-  (req_permissions & Permissions::Admin as i32) == Permissions::Admin as i32
-}
\ No newline at end of file
+pub(crate) fn is_admin(req: &Request<AppState>) -> bool {
+  request_permissions(req).contains(Permissions::ADMIN)
+}
+
+// set by the auth middleware, a route outside of it has no identity
+pub(crate) fn request_identity(req: &Request<AppState>) -> Option<&Identity> {
+  req.ext::<Identity>()
+}
+
+pub(crate) fn request_user(req: &Request<AppState>) -> Result<&str, ApiError> {
+  request_identity(req).map(|i| i.user.as_str()).ok_or(ApiError::Unauthorized)
+}
+
+pub(crate) fn request_permissions(req: &Request<AppState>) -> Permissions {
+  request_identity(req).map(|i| i.permissions).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::test_support::TestApp;
+
+  #[test]
+  fn expands_roles() {
+    let roles = vec!["viewer".to_string(), "cloud-manager".to_string()];
+    assert_eq!(Permissions::from_roles(&roles).unwrap(), Permissions::METRICS | Permissions::CLOUD | Permissions::CLOUD_MANAGE);
+    assert!(Permissions::from_roles(&["root".to_string()]).is_err());
+    assert_eq!(Permissions::from_i32(8 | 128), Permissions::CLOUD);
+    assert_eq!(Permissions::from_i32(72).names(), vec!["cloud", "cloud_manage"]);
+  }
+
+  #[async_std::test]
+  async fn lists_permissions_and_expands_roles() {
+    let app = TestApp::new();
+    let mut res = app.get("bob", "permissions").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let list: serde_json::Value = res.body_json().await.unwrap();
+    assert!(list["flags"].as_array().unwrap().contains(&json!({ "name": "cloud_manage", "bits": 64 })));
+    assert!(list["roles"].as_array().unwrap().iter().any(|r| r["name"] == "cloud-editor" && r["flags"] == json!(["cloud"])));
+
+    let mut res = app.post("bob", "keys").body(json!({ "name": "ci", "roles": ["cloud-editor"], "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(created["permissions"], 8);
+    let res = app.post("bob", "keys").body(json!({ "name": "ci", "roles": ["viewer"], "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 400);
+    let res = app.post("bob", "keys").body(json!({ "name": "ci", "roles": ["root"], "expires": 4102444800i64 })).await.unwrap();
+    assert_eq!(res.status(), 400);
+  }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tide::Request;
+
+use crate::{audit, cloud::SYSTEM_DIR, db::{create_record, delete_record, get_collection_records, get_record, modify_record, new_record_id, Filter, ModifyRecord}, error::ApiError, permissions::request_user, state::AppState};
+
+// every stored blob has a version record, the current files as well as old versions and the trash,
+// so all of them count for the user that wrote them. sizes are the uncompressed ones, keyed by record id.
+// writes that passed the quota check but aren't stored yet are held under their reservation id
+#[derive(Default)]
+pub(crate) struct UsageIndex {
+  blobs: HashMap<String, (String, String, u64)>,
+  totals: HashMap<(String, String), u64>,
+}
+
+// the fields of a cloud_versions record that matter here
+#[derive(Deserialize)]
+struct StoredVersion {
+  id: String,
+  path: String,
+  user: String,
+  size: u64,
+}
+
+// where a trashed item came from, its versions are moved below the key
+#[derive(Deserialize)]
+struct StoredTrash {
+  path: String,
+  key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Quota {
+  id: String,
+  user: String,
+  dir: String,
+  limit: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotaCreate {
+  #[serde(skip_deserializing)]
+  id: String,
+  user: String,
+  dir: String,
+  limit: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotaDelete {
+  id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuotaUpdate {
+  id: String,
+  user: String,
+  dir: String,
+  limit: u64,
+}
+
+#[derive(Serialize)]
+struct QuotaUsage {
+  id: String,
+  user: String,
+  dir: String,
+  limit: u64,
+  used: u64,
+}
+
+#[derive(Serialize)]
+struct Usage {
+  used: u64,
+  quotas: Vec<QuotaUsage>,
+}
+
+#[derive(Serialize)]
+struct QuotaExceeded {
+  user: String,
+  dir: String,
+  limit: u64,
+  used: u64,
+  required: u64,
+}
+
+impl ModifyRecord for QuotaUpdate {
+  fn id(&self) -> &String {
+    &self.id
+  }
+}
+
+impl UsageIndex {
+  // a blob moved into the trash keeps counting for the dir it was deleted from
+  fn insert(&mut self, id: String, user: String, path: &str, size: u64) {
+    let dir = match self.blobs.get(&id) {
+      Some((_, dir, _)) if is_system(path) => dir.clone(),
+      _ => top_dir(path),
+    };
+    self.remove(&id);
+    *self.totals.entry((user.clone(), dir.clone())).or_default() += size;
+    self.blobs.insert(id, (user, dir, size));
+  }
+
+  fn remove(&mut self, id: &str) {
+    if let Some((user, dir, size)) = self.blobs.remove(id) {
+      if let Some(total) = self.totals.get_mut(&(user, dir)) {
+        *total -= size;
+      }
+    }
+  }
+
+  fn used(&self, user: &str, dir: &str) -> u64 {
+    self.totals.iter()
+      .filter(|((u, d), _)| (user.is_empty() || u == user) && (dir.is_empty() || d == dir))
+      .map(|(_, size)| size)
+      .sum()
+  }
+}
+
+pub(crate) async fn get_quotas(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let quotas = get_collection_records::<Quota>(&state.db, "cloud_quotas", None).await?;
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&quotas)?).build())
+}
+
+pub(crate) async fn create_quota(mut req: Request<AppState>) -> tide::Result {
+  let mut new_quota: QuotaCreate = req.body_json().await?;
+  new_quota.id = new_record_id();
+  let state = req.state();
+  if new_quota.user.is_empty() && new_quota.dir.is_empty() || new_quota.dir.contains('/') {
+    return Ok(tide::Response::new(400));
+  }
+  let after = serde_json::to_value(&new_quota)?;
+  let target = new_quota.id.clone();
+  create_record(&state.db, "cloud_quotas", new_quota).await?;
+  audit::record(&req, "quota.create", &target, serde_json::Value::Null, after).await;
+  Ok(tide::Response::new(200))
+}
+
+pub(crate) async fn delete_quota(mut req: Request<AppState>) -> tide::Result {
+  let delete_quota: QuotaDelete = req.body_json().await?;
+  let state = req.state();
+  let before = get_record::<Quota>(&state.db, "cloud_quotas", &delete_quota.id).await?;
+  delete_record(&state.db, "cloud_quotas", delete_quota.id.clone()).await?;
+  audit::record(&req, "quota.delete", &delete_quota.id, serde_json::to_value(before)?, serde_json::Value::Null).await;
+  Ok(tide::Response::new(200))
+}
+
+pub(crate) async fn update_quota(mut req: Request<AppState>) -> tide::Result {
+  let modify_quota: QuotaUpdate = req.body_json().await?;
+  let state = req.state();
+  if modify_quota.user.is_empty() && modify_quota.dir.is_empty() || modify_quota.dir.contains('/') {
+    return Ok(tide::Response::new(400));
+  }
+  let before = get_record::<Quota>(&state.db, "cloud_quotas", &modify_quota.id).await?;
+  let after = serde_json::to_value(&modify_quota)?;
+  let target = modify_quota.id.clone();
+  modify_record(&state.db, "cloud_quotas", modify_quota).await?;
+  audit::record(&req, "quota.update", &target, serde_json::to_value(before)?, after).await;
+  Ok(tide::Response::new(200))
+}
+
+pub(crate) async fn get_usage(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let user = request_user(&req)?;
+  let quotas = get_collection_records::<Quota>(&state.db, "cloud_quotas", Some(Filter::eq("user", user).or(Filter::eq("user", "")))).await?;
+  let usage = state.usage.read().await;
+  let quotas = quotas.into_iter().map(|q| {
+    let used = usage.used(&q.user, &q.dir);
+    QuotaUsage { id: q.id, user: q.user, dir: q.dir, limit: q.limit, used }
+  }).collect();
+
+  let usage = Usage { used: usage.used(user, ""), quotas };
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&usage)?).build())
+}
+
+// returns the response to send instead of storing when the write would exceed a quota.
+// otherwise len is held under the reservation until release_quota, so concurrent writes can't all pass,
+// a file that gets replaced is kept as a version, so it doesn't free anything
+pub(crate) async fn reserve_quota(state: &AppState, reservation: &str, user: &str, path: &str, len: Option<u64>) -> tide::Result<Option<tide::Response>> {
+  let dir = top_dir(path);
+  let filter = Filter::eq("user", user).or(Filter::eq("user", "")).and(Filter::eq("dir", dir.as_str()).or(Filter::eq("dir", "")));
+  let quotas = get_collection_records::<Quota>(&state.db, "cloud_quotas", Some(filter)).await?;
+  let len = match len {
+    Some(l) => l,
+    None if quotas.is_empty() => return Ok(None),
+    None => return Ok(Some(ApiError::LengthRequired.response())),
+  };
+
+  let mut usage = state.usage.write().await;
+  for quota in quotas {
+    let used = usage.used(&quota.user, &quota.dir);
+    if used + len > quota.limit {
+      let exceeded = QuotaExceeded {
+        user: quota.user,
+        dir: quota.dir,
+        limit: quota.limit,
+        used,
+        required: len,
+      };
+      return Ok(Some(ApiError::QuotaExceeded(serde_json::to_value(&exceeded)?).response()));
+    }
+  }
+  usage.insert(reservation.to_string(), user.to_string(), path, len);
+  Ok(None)
+}
+
+// called once the write is stored or failed, a stored one is counted by its version record from then on
+pub(crate) async fn release_quota(state: &AppState, reservation: &str) {
+  state.usage.write().await.remove(reservation);
+}
+
+pub(crate) async fn record_usage(state: &AppState, id: &str, user: &str, path: &str, size: u64) {
+  state.usage.write().await.insert(id.to_string(), user.to_string(), path, size);
+}
+
+pub(crate) async fn remove_usage(state: &AppState, id: &str) {
+  state.usage.write().await.remove(id);
+}
+
+// sums the version records once at startup, later changes are applied as the records change
+pub(crate) async fn load_usage(state: &AppState) -> tide::Result<()> {
+  let versions = get_collection_records::<StoredVersion>(&state.db, "cloud_versions", None).await?;
+  let trash: HashMap<String, String> = get_collection_records::<StoredTrash>(&state.db, "cloud_trash", None).await?
+    .into_iter()
+    .map(|t| (t.key, t.path))
+    .collect();
+  let mut usage = state.usage.write().await;
+  for version in versions {
+    // trashed versions are below .cloud/trash/<id> and count where the item was deleted from
+    let key = version.path.splitn(4, '/').take(3).collect::<Vec<_>>().join("/");
+    let path = match trash.get(&key) {
+      Some(path) => format!("{}{}", path, &version.path[key.len()..]),
+      None => version.path,
+    };
+    usage.insert(version.id, version.user, &path, version.size);
+  }
+  Ok(())
+}
+
+fn is_system(path: &str) -> bool {
+  top_dir(path) == SYSTEM_DIR
+}
+
+fn top_dir(path: &str) -> String {
+  path.split('/').next().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::{config::Config, test_support::{Records, TestApp}};
+
+  #[async_std::test]
+  async fn counts_history_and_trash_against_quotas() {
+    let records = Records::default();
+    let app = TestApp::on(Config::default(), records.clone());
+    let res = app.post("root", "cloud/quotas").body(json!({ "user": "bob", "dir": "test", "limit": 10 })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let used = |app: TestApp| async move {
+      let mut res = app.get("bob", "cloud/usage").await.unwrap();
+      let usage: serde_json::Value = res.body_json().await.unwrap();
+      assert_eq!(usage["quotas"][0]["used"], usage["used"]);
+      usage["quotas"][0]["used"].as_u64().unwrap()
+    };
+
+    let res = app.post("bob", "cloud/files/test/a.txt").body("aaaaaa").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.post("bob", "cloud/files/test/b.txt").body("bbbbbb").await.unwrap();
+    assert_eq!(res.status(), 507);
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "quota_exceeded");
+    let body = surf::Body::from_reader(async_std::io::Cursor::new(b"b".to_vec()), None);
+    let res = app.post("bob", "cloud/files/test/b.txt").body(body).await.unwrap();
+    assert_eq!(res.status(), 411);
+
+    // the replaced content is kept as a version and still counts
+    let res = app.post("bob", "cloud/files/test/a.txt").body("aaa").await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(used(app.clone()).await, 9);
+    // the trash still counts for the dir the file was deleted from
+    let res = app.delete("bob", "cloud/files/test/a.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(used(app.clone()).await, 9);
+
+    // a restart sums the records instead of reading the files
+    let restarted = TestApp::on(Config::default(), records.clone());
+    load_usage(&restarted.state).await.unwrap();
+    assert_eq!(used(restarted).await, 9);
+
+    let res = app.delete("bob", "cloud/trash").body(json!({})).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(used(app.clone()).await, 0);
+  }
+
+  #[async_std::test]
+  async fn reserves_quota_for_writes_in_progress() {
+    use async_std::{io::{BufReader, WriteExt}, os::unix::net::UnixStream};
+
+    let app = TestApp::new();
+    let res = app.post("root", "cloud/quotas").body(json!({ "user": "bob", "dir": "test", "limit": 10 })).await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    // the space of a body that is still being sent is taken
+    let (mut writer, reader) = UnixStream::pair().unwrap();
+    let slow = app.post("bob", "cloud/files/test/slow.txt").body(surf::Body::from_reader(BufReader::new(reader), Some(6)));
+    let slow = async_std::task::spawn(slow.send());
+    for i in 0.. {
+      assert!(i < 500);
+      let mut res = app.get("bob", "cloud/usage").await.unwrap();
+      let usage: serde_json::Value = res.body_json().await.unwrap();
+      if usage["quotas"][0]["used"] == 6 {
+        break;
+      }
+      async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let res = app.post("bob", "cloud/files/test/fast.txt").body("ffffff").await.unwrap();
+    assert_eq!(res.status(), 507);
+    writer.write_all(b"ssssss").await.unwrap();
+    drop(writer);
+    let res = slow.await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    // as is the whole length of an unfinished resumable upload
+    let res = app.delete("bob", "cloud/files/test/slow.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/trash").body(json!({})).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.post("bob", "cloud/uploads").body(json!({ "path": "test/big.bin", "length": 8 })).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    let res = app.post("bob", "cloud/files/test/b.txt").body("bbb").await.unwrap();
+    assert_eq!(res.status(), 507);
+    let res = app.delete("bob", format!("cloud/uploads/{}", created["id"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.post("bob", "cloud/files/test/b.txt").body("bbb").await.unwrap();
+    assert_eq!(res.status(), 200);
+  }
+}
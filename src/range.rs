@@ -0,0 +1,103 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tide::{http::conditional::{ETag, IfModifiedSince, IfNoneMatch, LastModified}, Request, Response};
+
+use crate::storage::Metadata;
+
+pub(crate) struct Validators {
+  etag: ETag,
+  last_modified: LastModified,
+}
+
+pub(crate) enum Plan {
+  NotModified,
+  Full,
+  Partial(u64, u64),
+  Unsatisfiable,
+}
+
+impl Validators {
+  pub(crate) fn new(metadata: &Metadata) -> Self {
+    let nanos = metadata.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    Validators {
+      etag: ETag::new(format!("{:x}-{:x}", metadata.size, nanos)),
+      last_modified: LastModified::new(metadata.modified),
+    }
+  }
+
+  pub(crate) fn apply(&self, res: &mut Response) {
+    self.etag.apply(&mut *res);
+    self.last_modified.apply(res);
+  }
+
+  // size is only known for indexed files, everything else is always served in full
+  pub(crate) fn evaluate<State>(&self, req: &Request<State>, size: Option<u64>) -> Plan {
+    if let Ok(Some(if_none_match)) = IfNoneMatch::from_headers(req) {
+      if if_none_match.wildcard() || if_none_match.iter().any(|e| weak_eq(e, &self.etag)) {
+        return Plan::NotModified;
+      }
+    } else if let Ok(Some(if_modified_since)) = IfModifiedSince::from_headers(req) {
+      if unix_secs(self.last_modified.modified()) <= unix_secs(if_modified_since.modified()) {
+        return Plan::NotModified;
+      }
+    }
+
+    let (size, range) = match (size, req.header("Range")) {
+      (Some(size), Some(range)) => (size, range.as_str()),
+      _ => return Plan::Full,
+    };
+    if let Some(if_range) = req.header("If-Range") {
+      let if_range = if_range.as_str().trim();
+      let matches = if if_range.starts_with('"') || if_range.starts_with("W/") {
+        self.etag.is_strong() && self.etag.to_string() == if_range
+      } else {
+        self.last_modified.value().as_str() == if_range
+      };
+      if !matches {
+        return Plan::Full;
+      }
+    }
+
+    parse_range(range, size)
+  }
+}
+
+fn parse_range(range: &str, size: u64) -> Plan {
+  // multiple ranges are rare for media and allowed to be answered with the full body
+  let spec = match range.trim().strip_prefix("bytes=") {
+    Some(s) if !s.contains(',') => s.trim(),
+    _ => return Plan::Full,
+  };
+  let (start, end) = match spec.split_once('-') {
+    Some(s) => s,
+    None => return Plan::Full,
+  };
+
+  let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+    (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+    (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+    (Err(_), Ok(suffix)) if start.is_empty() => {
+      if suffix == 0 {
+        return Plan::Unsatisfiable;
+      }
+      (size.saturating_sub(suffix), size.saturating_sub(1))
+    },
+    _ => return Plan::Full,
+  };
+
+  if start >= size {
+    return Plan::Unsatisfiable;
+  }
+  Plan::Partial(start, end - start + 1)
+}
+
+fn weak_eq(a: &ETag, b: &ETag) -> bool {
+  let inner = |e: &ETag| match e {
+    ETag::Strong(s) | ETag::Weak(s) => s.clone(),
+  };
+  inner(a) == inner(b)
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,225 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::{config::{Config, Limit, RateLimitConfig}, error::ApiError, permissions::request_identity, state::AppState};
+
+// buckets that are full again carry no information and are dropped once there are this many
+const MAX_TRACKED: usize = 10_000;
+
+// runs before authentication, so failed logins and the public routes are covered too
+pub(crate) struct IpRateLimit {}
+
+// runs after authentication, when the user is known
+pub(crate) struct UserRateLimit {}
+
+pub(crate) struct RateLimits {
+  config: RateLimitConfig,
+  buckets: Mutex<HashMap<String, Bucket>>,
+  failures: Mutex<HashMap<String, Failures>>,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+struct Failures {
+  count: u32,
+  since: Instant,
+  locked_until: Option<Instant>,
+}
+
+impl Bucket {
+  // takes a token or returns how long it takes until the next one is available
+  fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), Duration> {
+    self.refill(limit, now);
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      return Ok(());
+    }
+    Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.rate))
+  }
+
+  fn refill(&mut self, limit: &Limit, now: Instant) {
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+    self.updated = now;
+  }
+}
+
+impl RateLimits {
+  pub(crate) fn new(config: &Config) -> Self {
+    RateLimits { config: config.rate_limit.clone(), buckets: Mutex::new(HashMap::new()), failures: Mutex::new(HashMap::new()) }
+  }
+
+  async fn take(&self, key: String, limit: &Limit) -> Result<(), Duration> {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().await;
+    if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&key) {
+      // the limit of a bucket is not stored, so the largest one decides when it counts as full
+      let refill = self.config.per_ip.burst.max(self.config.per_user.burst) as f64 / self.config.per_ip.rate.min(self.config.per_user.rate);
+      buckets.retain(|_, b| now.duration_since(b.updated).as_secs_f64() < refill);
+    }
+    buckets.entry(key).or_insert(Bucket { tokens: limit.burst as f64, updated: now }).take(limit, now)
+  }
+
+  async fn locked(&self, ip: &str) -> Option<Duration> {
+    let now = Instant::now();
+    let until = self.failures.lock().await.get(ip)?.locked_until?;
+    (until > now).then(|| until - now)
+  }
+
+  async fn record_failure(&self, ip: &str) {
+    let now = Instant::now();
+    let window = Duration::from_secs(self.config.failure_window);
+    let mut failures = self.failures.lock().await;
+    if failures.len() >= MAX_TRACKED && !failures.contains_key(ip) {
+      failures.retain(|_, f| now.duration_since(f.since) < window || f.locked_until.is_some_and(|u| u > now));
+    }
+    let entry = failures.entry(ip.to_string()).or_insert(Failures { count: 0, since: now, locked_until: None });
+    if now.duration_since(entry.since) >= window {
+      *entry = Failures { count: 0, since: now, locked_until: None };
+    }
+    entry.count += 1;
+    if entry.count >= self.config.max_auth_failures {
+      tide::log::warn!("Locking out {} after {} failed authentications", ip, entry.count);
+      entry.locked_until = Some(now + Duration::from_secs(self.config.lockout));
+      entry.count = 0;
+      entry.since = now;
+    }
+  }
+}
+
+pub(crate) fn client_ip(req: &Request<AppState>, trust_proxy: bool) -> String {
+  let addr = if trust_proxy { req.remote() } else { req.peer_addr() };
+  let addr = addr.unwrap_or("unknown");
+  match addr.parse::<std::net::SocketAddr>() {
+    Ok(a) => a.ip().to_string(),
+    Err(_) => addr.to_string(),
+  }
+}
+
+fn too_many(retry: Duration) -> Response {
+  // rounded up, retrying a moment too early would just fail again
+  let secs = retry.as_secs() + u64::from(retry.subsec_nanos() > 0);
+  let mut res = ApiError::TooManyRequests(secs).response();
+  res.insert_header("Retry-After", secs.to_string());
+  res
+}
+
+#[async_trait]
+impl Middleware<AppState> for IpRateLimit {
+  async fn handle(&self, req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+    let limits = req.state().limits.clone();
+    if !limits.config.enabled {
+      return Ok(next.run(req).await);
+    }
+
+    let ip = client_ip(&req, limits.config.trust_proxy);
+    if let Some(retry) = limits.locked(&ip).await {
+      return Ok(too_many(retry));
+    }
+    if let Err(retry) = limits.take(format!("ip:{}", ip), &limits.config.per_ip).await {
+      return Ok(too_many(retry));
+    }
+    let path = req.url().path().to_string();
+    for (i, group) in limits.config.groups.iter().enumerate().filter(|(_, g)| path.starts_with(&g.prefix)) {
+      let limit = Limit { rate: group.rate, burst: group.burst };
+      if let Err(retry) = limits.take(format!("group:{}:{}", i, ip), &limit).await {
+        return Ok(too_many(retry));
+      }
+    }
+
+    let res = next.run(req).await;
+    if res.status() == StatusCode::Unauthorized {
+      limits.record_failure(&ip).await;
+    }
+    Ok(res)
+  }
+}
+
+#[async_trait]
+impl Middleware<AppState> for UserRateLimit {
+  async fn handle(&self, req: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
+    let limits = req.state().limits.clone();
+    if let (true, Some(identity)) = (limits.config.enabled, request_identity(&req)) {
+      if let Err(retry) = limits.take(format!("user:{}", identity.user), &limits.config.per_user).await {
+        return Ok(too_many(retry));
+      }
+    }
+    Ok(next.run(req).await)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{config::{Config, GroupLimit}, test_support::TestApp};
+
+  #[test]
+  fn refills_over_time() {
+    let limit = Limit { rate: 2.0, burst: 3 };
+    let start = Instant::now();
+    let mut bucket = Bucket { tokens: 3.0, updated: start };
+    for _ in 0..3 {
+      assert!(bucket.take(&limit, start).is_ok());
+    }
+    let retry = bucket.take(&limit, start).unwrap_err();
+    assert_eq!(retry, Duration::from_millis(500));
+
+    assert!(bucket.take(&limit, start + Duration::from_millis(500)).is_ok());
+    assert!(bucket.take(&limit, start + Duration::from_millis(500)).is_err());
+    // never more than the burst, however long it was idle
+    let later = start + Duration::from_secs(60);
+    for _ in 0..3 {
+      assert!(bucket.take(&limit, later).is_ok());
+    }
+    assert!(bucket.take(&limit, later).is_err());
+  }
+
+  #[async_std::test]
+  async fn locks_out_after_repeated_failures() {
+    let mut config = Config::default();
+    config.rate_limit.max_auth_failures = 3;
+    let limits = RateLimits::new(&config);
+    for _ in 0..2 {
+      limits.record_failure("10.0.0.1").await;
+    }
+    assert!(limits.locked("10.0.0.1").await.is_none());
+    limits.record_failure("10.0.0.1").await;
+    assert!(limits.locked("10.0.0.1").await.unwrap() > Duration::from_secs(60));
+    assert!(limits.locked("10.0.0.2").await.is_none());
+  }
+
+  #[async_std::test]
+  async fn limits_requests_and_locks_out_guessing() {
+    let mut config = Config::default();
+    config.rate_limit.max_auth_failures = 3;
+    config.rate_limit.groups = vec![GroupLimit { prefix: "/cloud/direct/".to_string(), rate: 0.01, burst: 2 }];
+    let app = TestApp::with(config);
+
+    for _ in 0..2 {
+      let res = app.client.get("cloud/direct/00000000-0000-0000-0000-000000000000").await.unwrap();
+      assert_eq!(res.status(), 404);
+    }
+    let res = app.client.get("cloud/direct/00000000-0000-0000-0000-000000000000").await.unwrap();
+    assert_eq!(res.status(), 429);
+    assert!(res.header("Retry-After").unwrap().as_str().parse::<u64>().unwrap() > 0);
+
+    let res = app.get("bob", "cloud/usage").await.unwrap();
+    assert_eq!(res.status(), 200);
+    for _ in 0..3 {
+      let res = app.get("mallory", "cloud/usage").await.unwrap();
+      assert_eq!(res.status(), 401);
+    }
+    // the lockout applies to the whole source, valid tokens included
+    let mut res = app.get("bob", "cloud/usage").await.unwrap();
+    assert_eq!(res.status(), 429);
+    assert!(res.header("Retry-After").is_some());
+    let body: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(body["code"], "rate_limited");
+  }
+}
@@ -0,0 +1,44 @@
+use std::{collections::HashSet, sync::Arc};
+
+use async_std::sync::{Mutex, RwLock};
+
+use crate::{auth::AuthCache, config::Config, db::Db, events::Events, http::Upstreams, oidc::Oidc, quota::UsageIndex, rate_limit::RateLimits, storage::{self, Storage}};
+
+// everything a handler needs, cloning only clones the handles
+#[derive(Clone)]
+pub(crate) struct AppState {
+  pub(crate) config: Arc<Config>,
+  pub(crate) http: Upstreams,
+  pub(crate) db: Arc<Db>,
+  pub(crate) auth: Arc<AuthCache>,
+  pub(crate) oidc: Arc<Oidc>,
+  pub(crate) limits: Arc<RateLimits>,
+  pub(crate) storage: Arc<dyn Storage>,
+  pub(crate) usage: Arc<RwLock<UsageIndex>>,
+  pub(crate) active_uploads: Arc<Mutex<HashSet<String>>>,
+  pub(crate) events: Arc<Events>,
+}
+
+impl AppState {
+  pub(crate) fn new(config: Config) -> surf::Result<Self> {
+    let http = Upstreams::new(&config)?;
+    let db = Db::new(http.pocketbase.clone(), &config);
+    let storage = storage::from_config(&config, &http)?;
+    Ok(Self::from_parts(config, http, db, storage))
+  }
+
+  pub(crate) fn from_parts(config: Config, http: Upstreams, db: Db, storage: Arc<dyn Storage>) -> Self {
+    AppState {
+      auth: Arc::new(AuthCache::new(&config)),
+      oidc: Arc::new(Oidc::new(&config)),
+      limits: Arc::new(RateLimits::new(&config)),
+      config: Arc::new(config),
+      http,
+      db: Arc::new(db),
+      storage,
+      usage: Arc::new(RwLock::new(UsageIndex::default())),
+      active_uploads: Arc::new(Mutex::new(HashSet::new())),
+      events: Arc::new(Events::new()),
+    }
+  }
+}
@@ -0,0 +1,198 @@
+use std::{io::{Error, ErrorKind, SeekFrom}, path::PathBuf};
+
+use async_std::{fs::File, io::{BufReader, ReadExt, SeekExt, WriteExt}};
+use async_trait::async_trait;
+
+use super::{Entry, Metadata, Reader, Storage};
+
+pub(crate) struct LocalStorage {
+  root: String,
+}
+
+impl LocalStorage {
+  pub(crate) fn new(root: String) -> Self {
+    LocalStorage { root }
+  }
+
+  // symlinks are followed as long as they stay below the root, the part of the path that does
+  // not exist yet can't contain any
+  fn path(&self, path: &str) -> std::io::Result<PathBuf> {
+    let root = std::fs::canonicalize(&self.root)?;
+    let mut resolved = root.clone();
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    while let Some(segment) = segments.next() {
+      if segment == "." || segment == ".." {
+        return Err(escape());
+      }
+
+      resolved.push(segment);
+      match std::fs::canonicalize(&resolved) {
+        Ok(canonical) if canonical.starts_with(&root) => resolved = canonical,
+        Ok(_) => return Err(escape()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+          // a dangling symlink would be followed as soon as something is created through it
+          if std::fs::symlink_metadata(&resolved).is_ok() {
+            return Err(escape());
+          }
+          resolved.extend(segments);
+          break;
+        },
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(resolved)
+  }
+
+  async fn create_parent(&self, path: &str) -> std::io::Result<()> {
+    if let Some((parent, _)) = path.rsplit_once('/') {
+      async_std::fs::create_dir_all(self.path(parent)?).await?;
+    }
+    Ok(())
+  }
+
+  fn tmp_path(&self, path: &str) -> std::io::Result<PathBuf> {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    self.path(&super::join(parent, &format!(".{}.{}.tmp", name, rand::random::<u64>())))
+  }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+  async fn list(&self, dir: &str) -> std::io::Result<Vec<Entry>> {
+    let files = std::fs::read_dir(self.path(dir)?)?;
+    Ok(files.filter_map(|f| f.ok()).map(|f| Entry {
+      name: f.file_name().to_string_lossy().to_string(),
+      dir: f.file_type().map(|t| t.is_dir()).unwrap_or_default(),
+    }).collect())
+  }
+
+  async fn metadata(&self, path: &str) -> std::io::Result<Metadata> {
+    let metadata = async_std::fs::metadata(self.path(path)?).await?;
+    Ok(Metadata {
+      dir: metadata.is_dir(),
+      size: metadata.len(),
+      modified: metadata.modified()?,
+      created: metadata.created().ok(),
+    })
+  }
+
+  async fn read(&self, path: &str) -> std::io::Result<Reader> {
+    let file = File::open(self.path(path)?).await?;
+    Ok(Box::new(BufReader::new(file)))
+  }
+
+  async fn read_range(&self, path: &str, start: u64, len: u64) -> std::io::Result<Reader> {
+    let mut file = File::open(self.path(path)?).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(Box::new(BufReader::new(file.take(len))))
+  }
+
+  async fn write(&self, path: &str, reader: Reader, _len: u64) -> std::io::Result<()> {
+    self.create_parent(path).await?;
+    let tmp = self.tmp_path(path)?;
+    let mut file = File::create(&tmp).await?;
+    let res = async {
+      async_std::io::copy(reader, &mut file).await?;
+      file.flush().await?;
+      file.sync_all().await?;
+      async_std::fs::rename(&tmp, self.path(path)?).await
+    }.await;
+    if res.is_err() {
+      async_std::fs::remove_file(&tmp).await.ok();
+    }
+    res
+  }
+
+  async fn create_dir(&self, path: &str) -> std::io::Result<()> {
+    async_std::fs::create_dir_all(self.path(path)?).await
+  }
+
+  async fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+    self.create_parent(to).await?;
+    async_std::fs::rename(self.path(from)?, self.path(to)?).await
+  }
+
+  async fn delete_file(&self, path: &str) -> std::io::Result<()> {
+    async_std::fs::remove_file(self.path(path)?).await
+  }
+
+  async fn delete_dir(&self, path: &str) -> std::io::Result<()> {
+    async_std::fs::remove_dir_all(self.path(path)?).await
+  }
+
+  async fn import(&self, path: &str, local: &str) -> std::io::Result<()> {
+    self.create_parent(path).await?;
+    if async_std::fs::rename(local, self.path(path)?).await.is_ok() {
+      return Ok(());
+    }
+
+    let file = File::open(local).await?;
+    let len = file.metadata().await?.len();
+    self.write(path, Box::new(BufReader::new(file)), len).await?;
+    async_std::fs::remove_file(local).await.ok();
+    Ok(())
+  }
+}
+
+fn escape() -> Error {
+  Error::new(ErrorKind::PermissionDenied, "path leaves the storage root")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::symlink;
+
+  use async_std::io::Cursor;
+
+  use super::*;
+
+  fn setup() -> (PathBuf, LocalStorage) {
+    let base = std::env::temp_dir().join(format!("local-storage-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(base.join("root/inner")).unwrap();
+    std::fs::create_dir_all(base.join("outside")).unwrap();
+    std::fs::write(base.join("outside/secret"), "secret").unwrap();
+    symlink(base.join("outside"), base.join("root/out")).unwrap();
+    symlink(base.join("outside/secret"), base.join("root/secret")).unwrap();
+    symlink(base.join("missing"), base.join("root/dangling")).unwrap();
+    symlink(base.join("root/inner"), base.join("root/alias")).unwrap();
+    (base.clone(), LocalStorage::new(base.join("root").to_string_lossy().to_string()))
+  }
+
+  fn denied<T>(res: std::io::Result<T>) -> bool {
+    matches!(res, Err(e) if e.kind() == ErrorKind::PermissionDenied)
+  }
+
+  #[async_std::test]
+  async fn rejects_symlinks_leaving_the_root() {
+    let (base, storage) = setup();
+
+    assert!(denied(storage.list("out").await));
+    assert!(denied(storage.metadata("out/secret").await));
+    assert!(denied(storage.read("secret").await));
+    assert!(denied(storage.read_range("out/secret", 0, 1).await));
+    assert!(denied(storage.write("out/new", Box::new(Cursor::new(b"x".to_vec())), 1).await));
+    assert!(denied(storage.write("dangling/new", Box::new(Cursor::new(b"x".to_vec())), 1).await));
+    assert!(denied(storage.create_dir("dangling").await));
+    assert!(denied(storage.create_dir("out/new").await));
+    assert!(denied(storage.rename("inner", "out/inner").await));
+    assert!(denied(storage.delete_file("out/secret").await));
+    assert!(denied(storage.delete_dir("out").await));
+    assert!(denied(storage.list("inner/../../outside").await));
+
+    assert!(base.join("outside/secret").exists());
+    assert!(!base.join("outside/new").exists());
+    assert!(!base.join("missing").exists());
+    std::fs::remove_dir_all(base).unwrap();
+  }
+
+  #[async_std::test]
+  async fn follows_symlinks_inside_the_root() {
+    let (base, storage) = setup();
+
+    storage.write("alias/file", Box::new(Cursor::new(b"data".to_vec())), 4).await.unwrap();
+    assert_eq!(std::fs::read(base.join("root/inner/file")).unwrap(), b"data");
+    assert_eq!(storage.metadata("inner/file").await.unwrap().size, 4);
+    assert_eq!(storage.list("alias").await.unwrap().len(), 1);
+    std::fs::remove_dir_all(base).unwrap();
+  }
+}
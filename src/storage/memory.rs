@@ -0,0 +1,206 @@
+use std::{collections::BTreeMap, sync::Arc, time::SystemTime};
+
+use async_std::{io::{Cursor, ReadExt}, sync::RwLock};
+use async_trait::async_trait;
+
+use super::{not_found, Entry, Metadata, Reader, Storage};
+
+enum Node {
+  File(Arc<Vec<u8>>, SystemTime, SystemTime),
+  Dir(SystemTime),
+}
+
+// keeps everything in a flat map keyed by path, intended for tests and throwaway instances
+pub(crate) struct MemoryStorage {
+  nodes: RwLock<BTreeMap<String, Node>>,
+}
+
+impl MemoryStorage {
+  pub(crate) fn new() -> Self {
+    MemoryStorage { nodes: RwLock::new(BTreeMap::new()) }
+  }
+}
+
+fn is_child(path: &str, dir: &str) -> bool {
+  dir.is_empty() || path.starts_with(&format!("{}/", dir))
+}
+
+fn create_parents(nodes: &mut BTreeMap<String, Node>, path: &str) {
+  let mut parent = path;
+  while let Some((p, _)) = parent.rsplit_once('/') {
+    nodes.entry(p.to_string()).or_insert(Node::Dir(SystemTime::now()));
+    parent = p;
+  }
+}
+
+fn exists(nodes: &BTreeMap<String, Node>, path: &str) -> bool {
+  path.is_empty() || nodes.contains_key(path)
+}
+
+fn contents(nodes: &BTreeMap<String, Node>, path: &str) -> std::io::Result<Arc<Vec<u8>>> {
+  match nodes.get(path) {
+    Some(Node::File(data, ..)) => Ok(data.clone()),
+    _ => Err(not_found()),
+  }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+  async fn list(&self, dir: &str) -> std::io::Result<Vec<Entry>> {
+    let nodes = self.nodes.read().await;
+    if !exists(&nodes, dir) {
+      return Err(not_found());
+    }
+
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+    Ok(nodes.range(prefix.clone()..)
+      .take_while(|(p, _)| p.starts_with(&prefix))
+      .filter(|(p, _)| !p[prefix.len()..].contains('/'))
+      .map(|(p, n)| Entry { name: p[prefix.len()..].to_string(), dir: matches!(n, Node::Dir(_)) })
+      .collect())
+  }
+
+  async fn metadata(&self, path: &str) -> std::io::Result<Metadata> {
+    match self.nodes.read().await.get(path) {
+      Some(Node::File(data, modified, created)) => Ok(Metadata { dir: false, size: data.len() as u64, modified: *modified, created: Some(*created) }),
+      Some(Node::Dir(created)) => Ok(Metadata { dir: true, size: 0, modified: *created, created: Some(*created) }),
+      None if path.is_empty() => Ok(Metadata { dir: true, size: 0, modified: SystemTime::UNIX_EPOCH, created: None }),
+      None => Err(not_found()),
+    }
+  }
+
+  async fn read(&self, path: &str) -> std::io::Result<Reader> {
+    let data = contents(&*self.nodes.read().await, path)?;
+    Ok(Box::new(Cursor::new(data.to_vec())))
+  }
+
+  async fn read_range(&self, path: &str, start: u64, len: u64) -> std::io::Result<Reader> {
+    let data = contents(&*self.nodes.read().await, path)?;
+    let start = (start as usize).min(data.len());
+    let end = start.saturating_add(len as usize).min(data.len());
+    Ok(Box::new(Cursor::new(data[start..end].to_vec())))
+  }
+
+  async fn write(&self, path: &str, mut reader: Reader, len: u64) -> std::io::Result<()> {
+    let mut data = Vec::with_capacity(len as usize);
+    reader.read_to_end(&mut data).await?;
+
+    let mut nodes = self.nodes.write().await;
+    create_parents(&mut nodes, path);
+    let now = SystemTime::now();
+    let created = match nodes.get(path) {
+      Some(Node::File(_, _, created)) => *created,
+      _ => now,
+    };
+    nodes.insert(path.to_string(), Node::File(Arc::new(data), now, created));
+    Ok(())
+  }
+
+  async fn create_dir(&self, path: &str) -> std::io::Result<()> {
+    let mut nodes = self.nodes.write().await;
+    create_parents(&mut nodes, path);
+    nodes.entry(path.to_string()).or_insert(Node::Dir(SystemTime::now()));
+    Ok(())
+  }
+
+  async fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+    let mut nodes = self.nodes.write().await;
+    let node = nodes.remove(from).ok_or_else(not_found)?;
+    let children: Vec<String> = nodes.keys().filter(|p| is_child(p, from)).cloned().collect();
+
+    create_parents(&mut nodes, to);
+    nodes.insert(to.to_string(), node);
+    for child in children {
+      let node = nodes.remove(&child).unwrap();
+      nodes.insert(format!("{}{}", to, &child[from.len()..]), node);
+    }
+    Ok(())
+  }
+
+  async fn delete_file(&self, path: &str) -> std::io::Result<()> {
+    let mut nodes = self.nodes.write().await;
+    match nodes.get(path) {
+      Some(Node::File(..)) => {
+        nodes.remove(path);
+        Ok(())
+      },
+      _ => Err(not_found()),
+    }
+  }
+
+  async fn delete_dir(&self, path: &str) -> std::io::Result<()> {
+    let mut nodes = self.nodes.write().await;
+    if !matches!(nodes.get(path), Some(Node::Dir(_))) {
+      return Err(not_found());
+    }
+    nodes.retain(|p, _| p != path && !is_child(p, path));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::ErrorKind;
+
+  use super::*;
+
+  fn reader(data: &[u8]) -> Reader {
+    Box::new(Cursor::new(data.to_vec()))
+  }
+
+  async fn read_all(mut reader: Reader) -> Vec<u8> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await.unwrap();
+    data
+  }
+
+  fn names(entries: Vec<Entry>) -> Vec<(String, bool)> {
+    entries.into_iter().map(|e| (e.name, e.dir)).collect()
+  }
+
+  #[async_std::test]
+  async fn creates_parents_and_lists_direct_children() {
+    let storage = MemoryStorage::new();
+    storage.write("docs/sub/b.txt", reader(b"b"), 1).await.unwrap();
+    storage.write("docs/a.txt", reader(b"0123456789"), 10).await.unwrap();
+    storage.create_dir("empty").await.unwrap();
+
+    assert_eq!(names(storage.list("").await.unwrap()), vec![("docs".to_string(), true), ("empty".to_string(), true)]);
+    assert_eq!(names(storage.list("docs").await.unwrap()), vec![("a.txt".to_string(), false), ("sub".to_string(), true)]);
+    assert!(storage.list("empty").await.unwrap().is_empty());
+    assert_eq!(storage.list("missing").await.err().unwrap().kind(), ErrorKind::NotFound);
+
+    assert!(storage.metadata("").await.unwrap().dir);
+    assert!(storage.metadata("docs/sub").await.unwrap().dir);
+    assert_eq!(storage.metadata("docs/a.txt").await.unwrap().size, 10);
+    assert_eq!(read_all(storage.read_range("docs/a.txt", 8, 5).await.unwrap()).await, b"89");
+    assert!(read_all(storage.read_range("docs/a.txt", 20, 5).await.unwrap()).await.is_empty());
+
+    let created = storage.metadata("docs/a.txt").await.unwrap().created;
+    storage.write("docs/a.txt", reader(b"new"), 3).await.unwrap();
+    let replaced = storage.metadata("docs/a.txt").await.unwrap();
+    assert_eq!((replaced.size, replaced.created), (3, created));
+  }
+
+  #[async_std::test]
+  async fn moves_and_deletes_whole_trees() {
+    let storage = MemoryStorage::new();
+    storage.write("docs/a.txt", reader(b"a"), 1).await.unwrap();
+    storage.write("docs/sub/b.txt", reader(b"b"), 1).await.unwrap();
+    storage.write("docsx.txt", reader(b"x"), 1).await.unwrap();
+
+    storage.rename("docs", "archive/2024").await.unwrap();
+    assert_eq!(read_all(storage.read("archive/2024/sub/b.txt").await.unwrap()).await, b"b");
+    assert_eq!(storage.metadata("docs").await.err().unwrap().kind(), ErrorKind::NotFound);
+    // only shares a prefix with the directory
+    assert!(storage.metadata("docsx.txt").await.is_ok());
+    assert_eq!(storage.rename("docs", "other").await.err().unwrap().kind(), ErrorKind::NotFound);
+
+    assert_eq!(storage.delete_file("archive/2024").await.err().unwrap().kind(), ErrorKind::NotFound);
+    assert_eq!(storage.delete_dir("docsx.txt").await.err().unwrap().kind(), ErrorKind::NotFound);
+    storage.delete_dir("archive").await.unwrap();
+    assert_eq!(names(storage.list("").await.unwrap()), vec![("docsx.txt".to_string(), false)]);
+    storage.delete_file("docsx.txt").await.unwrap();
+    assert!(storage.list("").await.unwrap().is_empty());
+  }
+}
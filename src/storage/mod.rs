@@ -0,0 +1,72 @@
+use std::{io::{Error, ErrorKind}, sync::Arc, time::SystemTime};
+
+use async_std::{fs::File, io::{BufRead, BufReader}};
+use async_trait::async_trait;
+
+use crate::{config::Config, http::Upstreams};
+
+mod local;
+mod memory;
+mod s3;
+
+pub(crate) use local::LocalStorage;
+pub(crate) use memory::MemoryStorage;
+pub(crate) use s3::S3Storage;
+
+pub(crate) type Reader = Box<dyn BufRead + Unpin + Send + Sync>;
+
+pub(crate) struct Entry {
+  pub(crate) name: String,
+  pub(crate) dir: bool,
+}
+
+pub(crate) struct Metadata {
+  pub(crate) dir: bool,
+  pub(crate) size: u64,
+  pub(crate) modified: SystemTime,
+  pub(crate) created: Option<SystemTime>,
+}
+
+// paths are relative to the storage root, separated by '/' and without leading or trailing slashes
+#[async_trait]
+pub(crate) trait Storage: Send + Sync {
+  async fn list(&self, dir: &str) -> std::io::Result<Vec<Entry>>;
+  async fn metadata(&self, path: &str) -> std::io::Result<Metadata>;
+  async fn read(&self, path: &str) -> std::io::Result<Reader>;
+  async fn read_range(&self, path: &str, start: u64, len: u64) -> std::io::Result<Reader>;
+  // parent directories are created as needed and the file only becomes visible once it is complete
+  async fn write(&self, path: &str, reader: Reader, len: u64) -> std::io::Result<()>;
+  async fn create_dir(&self, path: &str) -> std::io::Result<()>;
+  async fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;
+  async fn delete_file(&self, path: &str) -> std::io::Result<()>;
+  async fn delete_dir(&self, path: &str) -> std::io::Result<()>;
+
+  // moves a finished local file into the storage, backends on the same disk can avoid the copy
+  async fn import(&self, path: &str, local: &str) -> std::io::Result<()> {
+    let file = File::open(local).await?;
+    let len = file.metadata().await?.len();
+    self.write(path, Box::new(BufReader::new(file)), len).await?;
+    async_std::fs::remove_file(local).await.ok();
+    Ok(())
+  }
+}
+
+pub(crate) fn from_config(config: &Config, http: &Upstreams) -> std::io::Result<Arc<dyn Storage>> {
+  Ok(match config.cloud.storage.as_str() {
+    "memory" => Arc::new(MemoryStorage::new()),
+    "s3" => Arc::new(S3Storage::new(&config.s3, http.s3.clone())?),
+    _ => Arc::new(LocalStorage::new(config.cloud.dir.clone())),
+  })
+}
+
+pub(crate) fn join(dir: &str, name: &str) -> String {
+  if dir.is_empty() {
+    name.to_string()
+  } else {
+    format!("{}/{}", dir, name)
+  }
+}
+
+pub(crate) fn not_found() -> Error {
+  Error::new(ErrorKind::NotFound, "not found")
+}
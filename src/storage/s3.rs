@@ -0,0 +1,493 @@
+use std::{io::{Error, ErrorKind}, time::SystemTime};
+
+use async_std::io::Cursor;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use surf::{http::Method, Body, Client, Url};
+
+use super::{join, not_found, Entry, Metadata, Reader, Storage};
+use crate::config::S3Config;
+
+const QUERY_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+const PATH_SET: &AsciiSet = &QUERY_SET.remove(b'/');
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+// talks to any S3 compatible object store using path style requests, directories are
+// represented by key prefixes and optional empty "dir/" marker objects
+pub(crate) struct S3Storage {
+  client: Client,
+  endpoint: String,
+  host: String,
+  bucket: String,
+  region: String,
+  access_key: String,
+  secret_key: String,
+  prefix: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<Object>,
+  #[serde(default)]
+  common_prefixes: Vec<CommonPrefix>,
+  next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Object {
+  key: String,
+  size: u64,
+  last_modified: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CommonPrefix {
+  prefix: String,
+}
+
+impl S3Storage {
+  pub(crate) fn new(config: &S3Config, client: Client) -> std::io::Result<Self> {
+    let endpoint = config.endpoint.trim_end_matches('/').to_string();
+    let url = Url::parse(&endpoint).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("s3 endpoint {}: {}", endpoint, e)))?;
+    let host = match (url.host_str(), url.port()) {
+      (Some(host), Some(port)) => format!("{}:{}", host, port),
+      (Some(host), None) => host.to_string(),
+      (None, _) => return Err(Error::new(ErrorKind::InvalidInput, format!("s3 endpoint {} has no host", endpoint))),
+    };
+
+    Ok(S3Storage {
+      client,
+      endpoint,
+      host,
+      bucket: config.bucket.clone(),
+      region: config.region.clone(),
+      access_key: config.access_key.clone(),
+      secret_key: config.secret_key.clone(),
+      prefix: config.prefix.trim_matches('/').to_string(),
+    })
+  }
+
+  fn key(&self, path: &str) -> String {
+    if path.is_empty() { self.prefix.clone() } else { join(&self.prefix, path) }
+  }
+
+  fn dir_prefix(&self, path: &str) -> String {
+    let key = self.key(path);
+    if key.is_empty() { key } else { format!("{}/", key) }
+  }
+
+  async fn send(&self, method: Method, key: &str, query: Vec<(&str, String)>, headers: Vec<(&str, String)>, body: Option<Body>) -> std::io::Result<surf::Response> {
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+
+    let uri = format!("/{}/{}", self.bucket, utf8_percent_encode(key, PATH_SET));
+    let mut query: Vec<(String, String)> = query.into_iter()
+      .map(|(k, v)| (utf8_percent_encode(k, QUERY_SET).to_string(), utf8_percent_encode(&v, QUERY_SET).to_string()))
+      .collect();
+    query.sort();
+    let query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join("&");
+
+    let mut signed: Vec<(String, String)> = headers.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+    signed.push(("host".to_string(), self.host.clone()));
+    signed.push(("x-amz-content-sha256".to_string(), UNSIGNED_PAYLOAD.to_string()));
+    signed.push(("x-amz-date".to_string(), amz_date.clone()));
+    signed.sort();
+
+    let canonical_headers: String = signed.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+    let signed_headers = signed.iter().map(|(k, _)| k.as_str()).collect::<Vec<&str>>().join(";");
+    let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, uri, query, canonical_headers, signed_headers, UNSIGNED_PAYLOAD);
+
+    let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(Sha256::digest(canonical_request)));
+    let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"].iter()
+      .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac(&key, part));
+    let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+      self.access_key, scope, signed_headers, hex::encode(hmac(&signing_key, &string_to_sign)));
+
+    let url = if query.is_empty() {
+      format!("{}{}", self.endpoint, uri)
+    } else {
+      format!("{}{}?{}", self.endpoint, uri, query)
+    };
+    let mut req = surf::Request::new(method, Url::parse(&url).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?);
+    for (name, value) in signed.into_iter().filter(|(k, _)| k != "host") {
+      req.insert_header(name.as_str(), value);
+    }
+    req.insert_header("Authorization", authorization);
+    if let Some(body) = body {
+      req.set_body(body);
+    }
+
+    let mut res = self.client.send(req).await.map_err(|e| Error::other(e.to_string()))?;
+    match res.status() as u16 {
+      200..=299 => Ok(res),
+      404 => Err(not_found()),
+      status => {
+        let body = res.body_string().await.unwrap_or_default();
+        Err(Error::other(format!("s3 request failed with {}: {}", status, body)))
+      },
+    }
+  }
+
+  async fn list_prefix(&self, prefix: &str, delimiter: bool, max_keys: Option<usize>) -> std::io::Result<(Vec<Object>, Vec<String>)> {
+    let mut keys = Vec::new();
+    let mut prefixes = Vec::new();
+    let mut token = None;
+    loop {
+      let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.to_string())];
+      if delimiter {
+        query.push(("delimiter", "/".to_string()));
+      }
+      if let Some(max_keys) = max_keys {
+        query.push(("max-keys", max_keys.to_string()));
+      }
+      if let Some(token) = token {
+        query.push(("continuation-token", token));
+      }
+
+      let mut res = self.send(Method::Get, "", query, Vec::new(), None).await?;
+      let body = res.body_string().await.map_err(|e| Error::other(e.to_string()))?;
+      let list: ListBucketResult = quick_xml::de::from_str(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+      keys.extend(list.contents);
+      prefixes.extend(list.common_prefixes.into_iter().map(|p| p.prefix));
+      token = list.next_continuation_token;
+      if token.is_none() || max_keys.is_some() {
+        return Ok((keys, prefixes));
+      }
+    }
+  }
+
+  async fn copy(&self, from: &str, to: &str) -> std::io::Result<()> {
+    let source = format!("/{}/{}", self.bucket, utf8_percent_encode(from, PATH_SET));
+    self.send(Method::Put, to, Vec::new(), vec![("x-amz-copy-source", source)], None).await?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+  async fn list(&self, dir: &str) -> std::io::Result<Vec<Entry>> {
+    let prefix = self.dir_prefix(dir);
+    let (keys, prefixes) = self.list_prefix(&prefix, true, None).await?;
+    if !dir.is_empty() && keys.is_empty() && prefixes.is_empty() {
+      return Err(not_found());
+    }
+
+    let dirs = prefixes.iter().map(|p| Entry { name: p[prefix.len()..].trim_end_matches('/').to_string(), dir: true });
+    let files = keys.iter().filter(|o| o.key != prefix).map(|o| Entry { name: o.key[prefix.len()..].to_string(), dir: false });
+    Ok(dirs.chain(files).collect())
+  }
+
+  async fn metadata(&self, path: &str) -> std::io::Result<Metadata> {
+    if path.is_empty() {
+      return Ok(Metadata { dir: true, size: 0, modified: SystemTime::UNIX_EPOCH, created: None });
+    }
+
+    // keys sharing the prefix sort after the exact key, so a single entry is enough to find the file
+    let key = self.key(path);
+    let (objects, _) = self.list_prefix(&key, false, Some(1)).await?;
+    if let Some(object) = objects.into_iter().find(|o| o.key == key) {
+      let modified = DateTime::parse_from_rfc3339(&object.last_modified).map(SystemTime::from).unwrap_or(SystemTime::UNIX_EPOCH);
+      return Ok(Metadata { dir: false, size: object.size, modified, created: None });
+    }
+
+    let (objects, prefixes) = self.list_prefix(&self.dir_prefix(path), true, Some(1)).await?;
+    if objects.is_empty() && prefixes.is_empty() {
+      return Err(not_found());
+    }
+    Ok(Metadata { dir: true, size: 0, modified: SystemTime::UNIX_EPOCH, created: None })
+  }
+
+  async fn read(&self, path: &str) -> std::io::Result<Reader> {
+    let mut res = self.send(Method::Get, &self.key(path), Vec::new(), Vec::new(), None).await?;
+    Ok(Box::new(res.take_body()))
+  }
+
+  async fn read_range(&self, path: &str, start: u64, len: u64) -> std::io::Result<Reader> {
+    if len == 0 {
+      return Ok(Box::new(Cursor::new(Vec::new())));
+    }
+
+    let range = format!("bytes={}-{}", start, start + len - 1);
+    let mut res = self.send(Method::Get, &self.key(path), Vec::new(), vec![("range", range)], None).await?;
+    Ok(Box::new(res.take_body()))
+  }
+
+  async fn write(&self, path: &str, reader: Reader, len: u64) -> std::io::Result<()> {
+    let body = Body::from_reader(reader, Some(len as usize));
+    self.send(Method::Put, &self.key(path), Vec::new(), Vec::new(), Some(body)).await?;
+    Ok(())
+  }
+
+  async fn create_dir(&self, path: &str) -> std::io::Result<()> {
+    self.send(Method::Put, &self.dir_prefix(path), Vec::new(), Vec::new(), Some(Body::empty())).await?;
+    Ok(())
+  }
+
+  async fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+    if !self.metadata(from).await?.dir {
+      self.copy(&self.key(from), &self.key(to)).await?;
+      return self.delete_file(from).await;
+    }
+
+    let (from_prefix, to_prefix) = (self.dir_prefix(from), self.dir_prefix(to));
+    let (objects, _) = self.list_prefix(&from_prefix, false, None).await?;
+    for object in &objects {
+      self.copy(&object.key, &format!("{}{}", to_prefix, &object.key[from_prefix.len()..])).await?;
+    }
+    for object in &objects {
+      self.send(Method::Delete, &object.key, Vec::new(), Vec::new(), None).await?;
+    }
+    Ok(())
+  }
+
+  async fn delete_file(&self, path: &str) -> std::io::Result<()> {
+    if self.metadata(path).await?.dir {
+      return Err(not_found());
+    }
+    self.send(Method::Delete, &self.key(path), Vec::new(), Vec::new(), None).await?;
+    Ok(())
+  }
+
+  async fn delete_dir(&self, path: &str) -> std::io::Result<()> {
+    let (objects, _) = self.list_prefix(&self.dir_prefix(path), false, None).await?;
+    if objects.is_empty() {
+      return Err(not_found());
+    }
+    for object in objects {
+      self.send(Method::Delete, &object.key, Vec::new(), Vec::new(), None).await?;
+    }
+    Ok(())
+  }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}};
+
+  use async_std::io::ReadExt;
+  use percent_encoding::percent_decode_str;
+  use tide::{http::Method, Request, Response};
+
+  use super::*;
+
+  const MODIFIED: &str = "2024-01-01T00:00:00.000Z";
+  // small enough that listings need continuation tokens
+  const PAGE_SIZE: usize = 2;
+
+  #[derive(Clone, Default)]
+  struct MockS3 {
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    ranges: Arc<Mutex<Vec<String>>>,
+  }
+
+  // recomputes the signature from what actually arrived, with the secret the storage should have used
+  fn signed(req: &Request<MockS3>) -> bool {
+    let verify = || -> Option<bool> {
+      let auth = req.header("Authorization")?.as_str().strip_prefix("AWS4-HMAC-SHA256 ")?.to_string();
+      let parts: HashMap<&str, &str> = auth.split(", ").filter_map(|p| p.split_once('=')).collect();
+      let (access_key, scope) = parts.get("Credential")?.split_once('/')?;
+      let url = req.url();
+      let host = format!("{}:{}", url.host_str()?, url.port()?);
+      let headers: String = parts.get("SignedHeaders")?.split(';').map(|name| {
+        let value = if name == "host" { host.clone() } else { req.header(name).map(|v| v.as_str().trim().to_string()).unwrap_or_default() };
+        format!("{}:{}\n", name, value)
+      }).collect();
+      let payload = req.header("x-amz-content-sha256")?.as_str();
+      let canonical = format!("{}\n{}\n{}\n{}\n{}\n{}", req.method(), url.path(), url.query().unwrap_or(""), headers, parts.get("SignedHeaders")?, payload);
+      let amz_date = req.header("x-amz-date")?.as_str();
+      let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(Sha256::digest(canonical)));
+      let key = scope.split('/').fold(b"AWS4secret".to_vec(), |key, part| hmac(&key, part));
+      Some(access_key == "access" && payload == UNSIGNED_PAYLOAD && scope == format!("{}/eu-central-1/s3/aws4_request", &amz_date[..8])
+        && hex::encode(hmac(&key, &string_to_sign)) == *parts.get("Signature")?)
+    };
+    verify().unwrap_or(false)
+  }
+
+  fn list(objects: &BTreeMap<String, Vec<u8>>, query: &HashMap<String, String>) -> String {
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    // a size of None marks a common prefix
+    let mut entries: Vec<(String, Option<usize>)> = Vec::new();
+    for (key, data) in objects.range(prefix.clone()..).take_while(|(k, _)| k.starts_with(&prefix)) {
+      match key[prefix.len()..].find('/') {
+        Some(i) if query.contains_key("delimiter") => {
+          let common = key[..prefix.len() + i + 1].to_string();
+          if entries.last().map(|(k, _)| k) != Some(&common) {
+            entries.push((common, None));
+          }
+        },
+        _ => entries.push((key.clone(), Some(data.len()))),
+      }
+    }
+
+    let start: usize = query.get("continuation-token").map_or(0, |t| t.parse().unwrap());
+    let page_size = query.get("max-keys").map_or(PAGE_SIZE, |m| m.parse().unwrap()).min(PAGE_SIZE);
+    let end = (start + page_size).min(entries.len());
+    let page = &entries[start.min(end)..end];
+    let contents: String = page.iter()
+      .filter_map(|(k, size)| size.map(|s| format!("<Contents><Key>{}</Key><Size>{}</Size><LastModified>{}</LastModified></Contents>", k, s, MODIFIED)))
+      .collect();
+    let prefixes: String = page.iter().filter(|(_, size)| size.is_none()).map(|(k, _)| format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", k)).collect();
+    let next = if end < entries.len() { format!("<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>", end) } else { String::new() };
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>cloud</Name>{}{}{}</ListBucketResult>", contents, prefixes, next)
+  }
+
+  async fn handle(mut req: Request<MockS3>) -> tide::Result {
+    if !signed(&req) {
+      return Ok(Response::builder(403).body("SignatureDoesNotMatch").build());
+    }
+    let key = match req.url().path().strip_prefix("/cloud/") {
+      Some(k) => percent_decode_str(k).decode_utf8()?.to_string(),
+      None => return Ok(Response::new(404)),
+    };
+    let state = req.state().clone();
+
+    match req.method() {
+      Method::Get if key.is_empty() => {
+        let query: HashMap<String, String> = req.url().query_pairs().into_owned().collect();
+        Ok(Response::builder(200).body(list(&state.objects.lock().unwrap(), &query)).build())
+      },
+      Method::Get => {
+        let data = match state.objects.lock().unwrap().get(&key) {
+          Some(d) => d.clone(),
+          None => return Ok(Response::new(404)),
+        };
+        let range = match req.header("range") {
+          Some(r) => r.as_str().to_string(),
+          None => return Ok(Response::builder(200).body(data).build()),
+        };
+        let (start, end) = range.strip_prefix("bytes=").and_then(|r| r.split_once('-')).unwrap();
+        let (start, end): (usize, usize) = (start.parse()?, end.parse()?);
+        state.ranges.lock().unwrap().push(range);
+        Ok(Response::builder(206).body(data[start..=end.min(data.len() - 1)].to_vec()).build())
+      },
+      Method::Put => {
+        let data = match req.header("x-amz-copy-source") {
+          Some(source) => {
+            let source = percent_decode_str(source.as_str().strip_prefix("/cloud/").unwrap()).decode_utf8()?.to_string();
+            match state.objects.lock().unwrap().get(&source) {
+              Some(d) => d.clone(),
+              None => return Ok(Response::new(404)),
+            }
+          },
+          None => req.body_bytes().await?,
+        };
+        state.objects.lock().unwrap().insert(key, data);
+        Ok(Response::new(200))
+      },
+      Method::Delete => {
+        state.objects.lock().unwrap().remove(&key);
+        Ok(Response::new(204))
+      },
+      _ => Ok(Response::new(405)),
+    }
+  }
+
+  fn storage(mock: &MockS3, secret: &str) -> S3Storage {
+    let mut server = tide::with_state(mock.clone());
+    server.at("/").all(handle);
+    server.at("*").all(handle);
+    let client: Client = surf::Config::new().set_http_client(server).try_into().unwrap();
+    let config = S3Config {
+      endpoint: "http://s3.test:9000/".to_string(),
+      region: "eu-central-1".to_string(),
+      access_key: "access".to_string(),
+      secret_key: secret.to_string(),
+      prefix: "/tenant/".to_string(),
+      ..S3Config::default()
+    };
+    S3Storage::new(&config, client).unwrap()
+  }
+
+  fn reader(data: &[u8]) -> Reader {
+    Box::new(Cursor::new(data.to_vec()))
+  }
+
+  async fn read_all(mut reader: Reader) -> Vec<u8> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).await.unwrap();
+    data
+  }
+
+  fn names(entries: Vec<Entry>) -> Vec<(String, bool)> {
+    entries.into_iter().map(|e| (e.name, e.dir)).collect()
+  }
+
+  #[async_std::test]
+  async fn reads_and_writes_objects_below_the_prefix() {
+    let mock = MockS3::default();
+    let storage = storage(&mock, "secret");
+    storage.write("docs/a b.txt", reader(b"0123456789"), 10).await.unwrap();
+    storage.write("docs/sub/b.txt", reader(b"b"), 1).await.unwrap();
+    storage.write("docs/z.txt", reader(b"z"), 1).await.unwrap();
+    storage.create_dir("empty").await.unwrap();
+    assert_eq!(mock.objects.lock().unwrap().keys().collect::<Vec<_>>(), vec!["tenant/docs/a b.txt", "tenant/docs/sub/b.txt", "tenant/docs/z.txt", "tenant/empty/"]);
+
+    // three entries take two pages
+    assert_eq!(names(storage.list("docs").await.unwrap()), vec![("sub".to_string(), true), ("a b.txt".to_string(), false), ("z.txt".to_string(), false)]);
+    assert_eq!(names(storage.list("").await.unwrap()), vec![("docs".to_string(), true), ("empty".to_string(), true)]);
+    assert!(storage.list("empty").await.unwrap().is_empty());
+    assert_eq!(storage.list("missing").await.err().unwrap().kind(), ErrorKind::NotFound);
+
+    let file = storage.metadata("docs/a b.txt").await.unwrap();
+    assert_eq!((file.dir, file.size), (false, 10));
+    assert_eq!(file.modified, SystemTime::from(DateTime::parse_from_rfc3339(MODIFIED).unwrap()));
+    assert!(storage.metadata("docs").await.unwrap().dir);
+    assert!(storage.metadata("empty").await.unwrap().dir);
+    // shares its prefix with docs, but is neither a file nor a directory
+    assert_eq!(storage.metadata("doc").await.err().unwrap().kind(), ErrorKind::NotFound);
+
+    assert_eq!(read_all(storage.read("docs/a b.txt").await.unwrap()).await, b"0123456789");
+    assert_eq!(read_all(storage.read_range("docs/a b.txt", 2, 3).await.unwrap()).await, b"234");
+    assert!(read_all(storage.read_range("docs/a b.txt", 2, 0).await.unwrap()).await.is_empty());
+    assert_eq!(*mock.ranges.lock().unwrap(), vec!["bytes=2-4"]);
+    assert_eq!(storage.read("docs/missing.txt").await.err().unwrap().kind(), ErrorKind::NotFound);
+  }
+
+  #[async_std::test]
+  async fn renames_and_deletes_by_prefix() {
+    let mock = MockS3::default();
+    let storage = storage(&mock, "secret");
+    storage.write("docs/a.txt", reader(b"a"), 1).await.unwrap();
+    storage.write("docs/sub/b.txt", reader(b"b"), 1).await.unwrap();
+    storage.write("docs/sub/c.txt", reader(b"c"), 1).await.unwrap();
+
+    storage.rename("docs/a.txt", "docs/renamed.txt").await.unwrap();
+    storage.rename("docs", "moved").await.unwrap();
+    assert_eq!(mock.objects.lock().unwrap().keys().collect::<Vec<_>>(), vec!["tenant/moved/renamed.txt", "tenant/moved/sub/b.txt", "tenant/moved/sub/c.txt"]);
+    assert_eq!(read_all(storage.read("moved/sub/c.txt").await.unwrap()).await, b"c");
+
+    assert_eq!(storage.delete_file("moved/sub").await.err().unwrap().kind(), ErrorKind::NotFound);
+    storage.delete_file("moved/renamed.txt").await.unwrap();
+    storage.delete_dir("moved").await.unwrap();
+    assert!(mock.objects.lock().unwrap().is_empty());
+    assert_eq!(storage.delete_dir("moved").await.err().unwrap().kind(), ErrorKind::NotFound);
+  }
+
+  #[async_std::test]
+  async fn signs_every_request() {
+    let mock = MockS3::default();
+    let err = storage(&mock, "not-the-secret").write("a.txt", reader(b"a"), 1).await.unwrap_err();
+    assert!(err.to_string().contains("403"), "{}", err);
+    assert!(mock.objects.lock().unwrap().is_empty());
+
+    let config = S3Config { endpoint: "not a url".to_string(), ..S3Config::default() };
+    assert_eq!(S3Storage::new(&config, Client::new()).err().unwrap().kind(), ErrorKind::InvalidInput);
+  }
+}
@@ -0,0 +1,325 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tide::Request;
+
+use crate::{audit, cloud::{check_path_permissions, token_scope, SYSTEM_DIR}, cloud_path::CloudPath, events::CloudEvent, db::{create_record, delete_record, get_collection_records, Filter}, permissions::{is_admin, request_user}, state::AppState, storage::join, versions::{delete_versions, move_versions}};
+
+#[derive(Serialize, Deserialize)]
+struct TrashItem {
+  id: String,
+  path: String,
+  key: String,
+  dir: bool,
+  user: String,
+  timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct TrashCreate {
+  path: String,
+  key: String,
+  dir: bool,
+  user: String,
+  timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct TrashInfo {
+  id: String,
+  path: String,
+  dir: bool,
+  timestamp: i64,
+}
+
+#[derive(Deserialize)]
+struct TrashRestore {
+  id: String,
+}
+
+#[derive(Deserialize)]
+struct TrashPurge {
+  id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TrashQuery {
+  user: Option<String>,
+}
+
+pub(crate) async fn get_trash(req: Request<AppState>) -> tide::Result {
+  let mut items = match get_user_items(&req).await? {
+    Ok(i) => i,
+    Err(r) => return Ok(r),
+  };
+  items.sort_by_key(|i| -i.timestamp);
+  let items: Vec<TrashInfo> = items.into_iter().map(|i| TrashInfo { id: i.id, path: i.path, dir: i.dir, timestamp: i.timestamp }).collect();
+
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&items)?).build())
+}
+
+pub(crate) async fn restore_trash(mut req: Request<AppState>) -> tide::Result {
+  let TrashRestore { id } = req.body_json().await?;
+  let state = req.state();
+  let items = match get_user_items(&req).await? {
+    Ok(i) => i,
+    Err(r) => return Ok(r),
+  };
+  let item = match items.into_iter().find(|i| i.id == id) {
+    Some(i) => i,
+    None => return Ok(tide::Response::new(404)),
+  };
+
+  // access could have been revoked since the item was deleted
+  let original = match CloudPath::parse(&item.path) {
+    Ok(p) => p,
+    Err(e) => return Ok(e.response()),
+  };
+  if let Err(r) = check_path_permissions(&req, original, item.dir, true).await {
+    return Ok(r);
+  }
+
+  let path = free_path(state, &item.path).await;
+  if let Some((parent, _)) = path.rsplit_once('/') {
+    state.storage.create_dir(parent).await?;
+  }
+  state.storage.rename(&item.key, &path).await?;
+  // the records of whatever was created at the old path in the meantime stay where they are
+  move_versions(state, &item.key, &path).await?;
+  delete_record(&state.db, "cloud_trash", item.id).await?;
+  audit::record(&req, "trash.restore", &path, serde_json::json!({ "path": item.path }), serde_json::json!({ "path": path })).await;
+  if let Ok(restored) = CloudPath::parse(&path) {
+    state.events.publish(CloudEvent::created(&restored, item.dir)).await;
+  }
+
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&path)?).build())
+}
+
+pub(crate) async fn purge_trash(mut req: Request<AppState>) -> tide::Result {
+  let TrashPurge { id } = req.body_json().await?;
+  let state = req.state();
+  if token_scope(&req)?.is_some_and(|(_, write)| !write) {
+    return Ok(tide::Response::new(403));
+  }
+  let items = match get_user_items(&req).await? {
+    Ok(i) => i,
+    Err(r) => return Ok(r),
+  };
+  let items: Vec<TrashItem> = match id {
+    Some(id) => items.into_iter().filter(|i| i.id == id).collect(),
+    None => items,
+  };
+  if items.is_empty() {
+    return Ok(tide::Response::new(404));
+  }
+
+  for item in items {
+    let path = item.path.clone();
+    purge_item(state, item).await?;
+    audit::record(&req, "trash.purge", &path, serde_json::Value::Null, serde_json::Value::Null).await;
+  }
+  Ok(tide::Response::new(200))
+}
+
+pub(crate) async fn move_to_trash(state: &AppState, path: &str, dir: bool, user: &str) -> tide::Result<()> {
+  let key = join(&format!("{}/trash", SYSTEM_DIR), &rand::random::<u128>().to_string());
+  state.storage.rename(path, &key).await?;
+  // the history goes along, a file created at the same path later starts a new one
+  move_versions(state, path, &key).await?;
+
+  let item = TrashCreate { path: path.to_string(), key, dir, user: user.to_string(), timestamp: chrono::Utc::now().timestamp() };
+  create_record(&state.db, "cloud_trash", item).await?;
+  Ok(())
+}
+
+pub(crate) async fn purge_expired(state: AppState) {
+  loop {
+    if state.config.cloud.trash_max_age > 0 {
+      let cutoff = chrono::Utc::now().timestamp() - state.config.cloud.trash_max_age as i64 * 24 * 60 * 60;
+      match get_collection_records::<TrashItem>(&state.db, "cloud_trash", Some(Filter::lt("timestamp", cutoff))).await {
+        Ok(items) => {
+          for item in items {
+            if let Err(e) = purge_item(&state, item).await {
+              tide::log::error!("Failed to purge trash item: {}", e);
+            }
+          }
+        },
+        Err(e) => tide::log::error!("Failed to load expired trash items: {}", e),
+      }
+    }
+    async_std::task::sleep(Duration::from_secs(60 * 60)).await;
+  }
+}
+
+async fn purge_item(state: &AppState, item: TrashItem) -> tide::Result<()> {
+  let res = if item.dir {
+    state.storage.delete_dir(&item.key).await
+  } else {
+    state.storage.delete_file(&item.key).await
+  };
+  if let Err(e) = res {
+    if e.kind() != std::io::ErrorKind::NotFound {
+      return Err(e.into());
+    }
+  }
+  delete_versions(state, &item.key).await?;
+  delete_record(&state.db, "cloud_trash", item.id).await?;
+  Ok(())
+}
+
+// only ever the trash of one user, admins name someone else's with ?user= and that is audited
+async fn get_user_items(req: &Request<AppState>) -> tide::Result<Result<Vec<TrashItem>, tide::Response>> {
+  let state = req.state();
+  let user = request_user(req)?;
+  let TrashQuery { user: owner } = req.query()?;
+  let owner = match owner {
+    Some(o) if o != user => {
+      if !is_admin(req) {
+        return Ok(Err(tide::Response::new(403)));
+      }
+      audit::record(req, "trash.access", &o, serde_json::Value::Null, serde_json::Value::Null).await;
+      o
+    },
+    _ => user.to_string(),
+  };
+  let items = get_collection_records::<TrashItem>(&state.db, "cloud_trash", Some(Filter::eq("user", owner))).await?;
+  Ok(Ok(match token_scope(req)? {
+    Some((scope, _)) => items.into_iter().filter(|i| CloudPath::parse(&i.path).is_ok_and(|p| p.is_within(&scope))).collect(),
+    None => items,
+  }))
+}
+
+// a restored item never overwrites what was created at its old path in the meantime
+async fn free_path(state: &AppState, path: &str) -> String {
+  let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+  let (stem, ext) = match name.rsplit_once('.') {
+    Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+    _ => (name, String::new()),
+  };
+
+  let mut candidate = path.to_string();
+  let mut i = 1;
+  while state.storage.metadata(&candidate).await.is_ok() {
+    candidate = join(parent, &format!("{} ({}){}", stem, i, ext));
+    i += 1;
+  }
+  candidate
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{config::Config, test_support::{token, Records, TestApp}};
+
+  #[async_std::test]
+  async fn restores_trash_next_to_newer_files() {
+    let records = Records::default();
+    let app = TestApp::on(Config::default(), records.clone());
+    for content in ["v1", "v2"] {
+      let res = app.post("bob", "cloud/files/test/a.txt").body(content).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    let res = app.delete("bob", "cloud/files/test/a.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.post("bob", "cloud/files/test/a.txt").body("new").await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    // the trashed history is not mixed into the one of the new file
+    let mut res = app.get("bob", "cloud/versions/test/a.txt").await.unwrap();
+    let versions: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!(versions.len(), 1);
+
+    let mut res = app.get("bob", "cloud/trash").await.unwrap();
+    let items: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!((items.len(), &items[0]["path"]), (1, &json!("test/a.txt")));
+    let mut res = app.post("bob", "cloud/trash").body(json!({ "id": items[0]["id"] })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body_json::<String>().await.unwrap(), "test/a (1).txt");
+
+    let mut res = app.get("bob", "cloud/files/test/a.txt").await.unwrap();
+    assert_eq!(res.body_string().await.unwrap(), "new");
+    let mut res = app.get("bob", "cloud/files/test/a%20(1).txt").await.unwrap();
+    assert_eq!(res.body_string().await.unwrap(), "v2");
+    let mut res = app.get("bob", "cloud/versions/test/a%20(1).txt").await.unwrap();
+    let versions: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!(versions.len(), 2);
+    let res = app.post("bob", "cloud/trash").body(json!({ "id": items[0]["id"] })).await.unwrap();
+    assert_eq!(res.status(), 404);
+
+    let res = app.post("bob", "cloud/files/test/docs/b.txt").body("b").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/dirs/test/docs").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.post("bob", "cloud/dirs/test/docs").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.get("bob", "cloud/trash").await.unwrap();
+    let items: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    let mut res = app.post("bob", "cloud/trash").body(json!({ "id": items[0]["id"] })).await.unwrap();
+    assert_eq!(res.body_json::<String>().await.unwrap(), "test/docs (1)");
+    let mut res = app.get("bob", "cloud/versions/test/docs%20(1)/b.txt").await.unwrap();
+    let versions: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!(versions.len(), 1);
+  }
+
+  #[async_std::test]
+  async fn purges_trash_with_its_history() {
+    let records = Records::default();
+    let app = TestApp::on(Config::default(), records.clone());
+    let versions = || records.lock().unwrap().iter().filter(|(c, _)| c == "cloud_versions").map(|(_, r)| r["path"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+    for content in ["v1", "v2"] {
+      let res = app.post("bob", "cloud/files/test/a.txt").body(content).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    let res = app.post("bob", "cloud/files/test/keep.txt").body("keep").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.delete("bob", "cloud/files/test/a.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+    let trashed = versions();
+    assert_eq!(trashed.len(), 3);
+    assert_eq!(trashed.iter().filter(|p| p.starts_with(".cloud/trash/")).count(), 2);
+
+    let res = app.delete("root", "cloud/trash").body(json!({ "id": "unknown" })).await.unwrap();
+    assert_eq!(res.status(), 404);
+    let res = app.delete("bob", "cloud/trash").body(json!({})).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(versions(), vec!["test/keep.txt"]);
+    assert!(!records.lock().unwrap().iter().any(|(c, _)| c == "cloud_trash"));
+  }
+
+  #[async_std::test]
+  async fn keeps_the_trash_of_each_user_apart() {
+    let app = TestApp::new();
+    for user in ["bob", "root"] {
+      let path = format!("cloud/files/test/{}.txt", user);
+      let res = app.client.post(&path).header("Authorization", token(user)).body(user).await.unwrap();
+      assert_eq!(res.status(), 200);
+      let res = app.client.delete(&path).header("Authorization", token(user)).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    let trash = |user: &'static str, query: &'static str| {
+      let app = app.clone();
+      async move {
+        let mut res = app.client.get(format!("cloud/trash{}", query)).header("Authorization", token(user)).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let items: serde_json::Value = res.body_json().await.unwrap();
+        items.as_array().unwrap().iter().map(|i| i["path"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+      }
+    };
+    assert_eq!(trash("root", "").await, ["test/root.txt"]);
+
+    // emptying an admin's trash leaves everyone else's alone
+    let res = app.delete("root", "cloud/trash").body(json!({})).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(trash("root", "").await.is_empty());
+    assert_eq!(trash("bob", "").await, ["test/bob.txt"]);
+
+    // another user's trash has to be asked for, and that is audited
+    assert_eq!(trash("root", "?user=bob").await, ["test/bob.txt"]);
+    let res = app.get("bob", "cloud/trash?user=root").await.unwrap();
+    assert_eq!(res.status(), 403);
+    let mut res = app.get("root", "audit?action=trash.access").await.unwrap();
+    let entries: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!((entries[0]["actor"].as_str(), entries[0]["target"].as_str()), (Some("root"), Some("bob")));
+  }
+}
@@ -0,0 +1,444 @@
+use std::time::Duration;
+
+use async_std::{fs::OpenOptions, io::{BufReader, ReadExt, WriteExt}, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use tide::Request;
+
+use crate::{cloud::{check_path_permissions, store_file, token_scope}, cloud_path::CloudPath, permissions::request_user, quota::{record_usage, release_quota, reserve_quota}, state::AppState};
+
+#[derive(Serialize, Deserialize)]
+struct UploadSession {
+  id: String,
+  path: String,
+  dir: String,
+  user: String,
+  length: u64,
+  offset: u64,
+  created: i64,
+}
+
+#[derive(Deserialize)]
+struct UploadCreate {
+  path: String,
+  length: u64,
+}
+
+#[derive(Serialize)]
+struct UploadStatus {
+  id: String,
+  path: String,
+  length: u64,
+  offset: u64,
+  complete: bool,
+}
+
+impl From<&UploadSession> for UploadStatus {
+  fn from(session: &UploadSession) -> Self {
+    UploadStatus {
+      id: session.id.clone(),
+      path: session.path.clone(),
+      length: session.length,
+      offset: session.offset,
+      complete: session.offset == session.length,
+    }
+  }
+}
+
+pub(crate) async fn create_upload(mut req: Request<AppState>) -> tide::Result {
+  let UploadCreate { path, length } = req.body_json().await?;
+  let state = req.state();
+  let path = match CloudPath::parse(&path) {
+    Ok(p) => p,
+    Err(e) => return Ok(e.response()),
+  };
+  let (path, dir) = match check_path_permissions(&req, path, false, true).await {
+    Ok(p) => p,
+    Err(r) => return Ok(r),
+  };
+
+  let user = request_user(&req)?.to_string();
+  let id = rand::random::<u128>().to_string();
+  // the length stays reserved until the upload is finished or dropped
+  if let Some(res) = reserve_quota(state, &reservation(&id), &user, &path, Some(length)).await? {
+    return Ok(res);
+  }
+
+  let session = UploadSession {
+    id,
+    path: path.to_string(),
+    dir: dir.to_string(),
+    user,
+    length,
+    offset: 0,
+    created: chrono::Utc::now().timestamp(),
+  };
+
+  if let Err(e) = start_session(state, &session).await {
+    remove_session(state, &session.id).await;
+    return Err(e);
+  }
+
+  status_response(201, &session)
+}
+
+pub(crate) async fn get_upload(req: Request<AppState>) -> tide::Result {
+  let session = match load_session(&req).await? {
+    Ok(s) => s,
+    Err(r) => return Ok(r),
+  };
+
+  status_response(200, &session)
+}
+
+pub(crate) async fn append_upload(mut req: Request<AppState>) -> tide::Result {
+  let id = req.param("id").unwrap_or_default().to_string();
+  if !claim(req.state(), &id).await {
+    return Ok(tide::Response::new(423));
+  }
+  let res = match load_session(&req).await {
+    Ok(Ok(mut session)) => append_chunk(&mut req, &mut session).await,
+    Ok(Err(r)) => Ok(r),
+    Err(e) => Err(e),
+  };
+  release(req.state(), &id).await;
+  res
+}
+
+pub(crate) async fn delete_upload(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let id = req.param("id").unwrap_or_default().to_string();
+  if !claim(state, &id).await {
+    return Ok(tide::Response::new(423));
+  }
+  let res = match load_session(&req).await {
+    Ok(Ok(session)) => {
+      remove_session(state, &session.id).await;
+      Ok(tide::Response::new(200))
+    },
+    Ok(Err(r)) => Ok(r),
+    Err(e) => Err(e),
+  };
+  release(state, &id).await;
+  res
+}
+
+// a session is only read and changed while its slot is held, so no request works with an offset another one already moved
+async fn claim(state: &AppState, id: &str) -> bool {
+  state.active_uploads.lock().await.insert(id.to_string())
+}
+
+async fn release(state: &AppState, id: &str) {
+  state.active_uploads.lock().await.remove(id);
+}
+
+async fn append_chunk(req: &mut Request<AppState>, session: &mut UploadSession) -> tide::Result {
+  // the body has to be taken while the state is still needed
+  let state = req.state().clone();
+  reconcile_part(&state, session).await?;
+
+  let offset: u64 = match req.header("Upload-Offset").and_then(|o| o.as_str().parse().ok()) {
+    Some(o) => o,
+    None => return Ok(tide::Response::new(400)),
+  };
+  if offset != session.offset {
+    return status_response(409, session);
+  }
+
+  let remaining = session.length - session.offset;
+  if req.len().is_some_and(|len| len as u64 > remaining) {
+    return Ok(tide::Response::new(413));
+  }
+
+  let mut file = OpenOptions::new().append(true).open(part_path(&state, &session.id)).await?;
+  let mut body = req.take_body();
+  let written = async_std::io::copy((&mut body).take(remaining), &mut file).await?;
+  // a chunk without a length can only be found to be too long once the upload is full
+  if written == remaining && body.read(&mut [0]).await? > 0 {
+    file.set_len(session.offset).await?;
+    return Ok(tide::Response::new(413));
+  }
+  file.sync_all().await?;
+
+  session.offset += written;
+  save_session(&state, session).await?;
+
+  if session.offset == session.length {
+    finish_upload(&state, session).await?;
+  }
+
+  status_response(204, session)
+}
+
+pub(crate) async fn purge_abandoned(state: AppState) {
+  loop {
+    if let Err(e) = remove_abandoned(&state).await {
+      tide::log::error!("Failed to remove abandoned uploads: {}", e);
+    }
+    async_std::task::sleep(Duration::from_secs(60 * 60)).await;
+  }
+}
+
+// drops sessions that were started too long ago, and parts whose session was never saved
+pub(crate) async fn remove_abandoned(state: &AppState) -> tide::Result<()> {
+  if state.config.cloud.upload_max_age == 0 {
+    return Ok(());
+  }
+  let cutoff = chrono::Utc::now().timestamp() - state.config.cloud.upload_max_age as i64 * 24 * 60 * 60;
+  let mut entries = match async_std::fs::read_dir(&state.config.cloud.upload_dir).await {
+    Ok(e) => e,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e.into()),
+  };
+  while let Some(entry) = entries.next().await {
+    let name = entry?.file_name().to_string_lossy().to_string();
+    let id = match name.strip_suffix(".json").or(name.strip_suffix(".part")) {
+      Some(id) if id.parse::<u128>().is_ok() => id.to_string(),
+      _ => continue,
+    };
+    if !claim(state, &id).await {
+      continue;
+    }
+    let created = match async_std::fs::read(session_path(state, &id)).await {
+      Ok(data) => serde_json::from_slice::<UploadSession>(&data).ok().map(|s| s.created),
+      Err(_) => async_std::fs::metadata(part_path(state, &id)).await.ok().and_then(|m| m.modified().ok()).map(|t| chrono::DateTime::<chrono::Utc>::from(t).timestamp()),
+    };
+    if created.is_some_and(|c| c < cutoff) {
+      remove_session(state, &id).await;
+    }
+    release(state, &id).await;
+  }
+  Ok(())
+}
+
+// the reservations are only kept in memory, so the ones of unfinished sessions are taken again at startup
+pub(crate) async fn load_reservations(state: &AppState) -> tide::Result<()> {
+  let mut entries = match async_std::fs::read_dir(&state.config.cloud.upload_dir).await {
+    Ok(e) => e,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e.into()),
+  };
+  while let Some(entry) = entries.next().await {
+    let path = entry?.path();
+    if path.extension().is_none_or(|e| e != "json") {
+      continue;
+    }
+    if let Ok(session) = serde_json::from_slice::<UploadSession>(&async_std::fs::read(&path).await?) {
+      record_usage(state, &reservation(&session.id), &session.user, &session.path, session.length).await;
+    }
+  }
+  Ok(())
+}
+
+async fn start_session(state: &AppState, session: &UploadSession) -> tide::Result<()> {
+  async_std::fs::create_dir_all(&state.config.cloud.upload_dir).await?;
+  async_std::fs::write(part_path(state, &session.id), []).await?;
+  save_session(state, session).await?;
+  if session.length == 0 {
+    finish_upload(state, session).await?;
+  }
+  Ok(())
+}
+
+async fn load_session(req: &Request<AppState>) -> tide::Result<Result<UploadSession, tide::Response>> {
+  let state = req.state();
+  let id = req.param("id").unwrap_or_default();
+  if id.parse::<u128>().is_err() {
+    return Ok(Err(tide::Response::new(404)));
+  }
+
+  let data = match async_std::fs::read(session_path(state, id)).await {
+    Ok(d) => d,
+    Err(_) => return Ok(Err(tide::Response::new(404))),
+  };
+  let session: UploadSession = serde_json::from_slice(&data)?;
+  if session.user != request_user(req)? {
+    return Ok(Err(tide::Response::new(403)));
+  }
+  // sessions of the same user can be outside of what a scoped token may touch
+  if let Some((scope, write)) = token_scope(req)? {
+    if !write || !CloudPath::parse(&session.path).is_ok_and(|p| p.is_within(&scope)) {
+      return Ok(Err(tide::Response::new(403)));
+    }
+  }
+
+  Ok(Ok(session))
+}
+
+async fn reconcile_part(state: &AppState, session: &mut UploadSession) -> tide::Result<()> {
+  // a chunk that was only partially written before a restart was never acknowledged, so drop it
+  let file = OpenOptions::new().write(true).open(part_path(state, &session.id)).await?;
+  let len = file.metadata().await?.len();
+  if len > session.offset {
+    file.set_len(session.offset).await?;
+  } else if len < session.offset {
+    session.offset = len;
+    save_session(state, session).await?;
+  }
+  Ok(())
+}
+
+async fn save_session(state: &AppState, session: &UploadSession) -> tide::Result<()> {
+  let tmp = format!("{}.tmp", session_path(state, &session.id));
+  let mut file = async_std::fs::File::create(&tmp).await?;
+  file.write_all(&serde_json::to_vec(session)?).await?;
+  file.sync_all().await?;
+  async_std::fs::rename(tmp, session_path(state, &session.id)).await?;
+  Ok(())
+}
+
+async fn remove_session(state: &AppState, id: &str) {
+  async_std::fs::remove_file(part_path(state, id)).await.ok();
+  async_std::fs::remove_file(session_path(state, id)).await.ok();
+  release_quota(state, &reservation(id)).await;
+}
+
+async fn finish_upload(state: &AppState, session: &UploadSession) -> tide::Result<()> {
+  let part = async_std::fs::File::open(part_path(state, &session.id)).await?;
+  store_file(state, BufReader::new(part), &session.path, &session.user).await?;
+  remove_session(state, &session.id).await;
+  Ok(())
+}
+
+fn status_response(status: u16, session: &UploadSession) -> tide::Result {
+  let mut res = tide::Response::builder(status)
+    .header("Upload-Offset", session.offset.to_string())
+    .header("Upload-Length", session.length.to_string());
+  if status != 204 {
+    res = res.body(tide::Body::from_json(&UploadStatus::from(session))?);
+  }
+  Ok(res.build())
+}
+
+fn reservation(id: &str) -> String {
+  format!("session/{}", id)
+}
+
+fn session_path(state: &AppState, id: &str) -> String {
+  format!("{}/{}.json", state.config.cloud.upload_dir, id)
+}
+
+fn part_path(state: &AppState, id: &str) -> String {
+  format!("{}/{}.part", state.config.cloud.upload_dir, id)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+  use crate::{config::Config, test_support::TestApp};
+
+  #[async_std::test]
+  async fn resumes_uploads_at_their_offset() {
+    use async_std::{io::{BufReader, WriteExt}, os::unix::net::UnixStream};
+
+    let app = TestApp::new();
+    let mut res = app.post("bob", "cloud/uploads").body(json!({ "path": "test/big.bin", "length": 10 })).await.unwrap();
+    assert_eq!(res.status(), 201);
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    let url = format!("cloud/uploads/{}", created["id"].as_str().unwrap());
+
+    let res = app.patch("bob", &url).header("Upload-Offset", "3").body("hello").await.unwrap();
+    assert_eq!(res.status(), 409);
+    assert_eq!(res.header("Upload-Offset").unwrap().as_str(), "0");
+
+    // a chunk that is still being sent holds the session
+    let (mut writer, reader) = UnixStream::pair().unwrap();
+    let slow = app.patch("bob", &url).header("Upload-Offset", "0").body(surf::Body::from_reader(BufReader::new(reader), None));
+    let slow = async_std::task::spawn(slow.send());
+    // polling with requests would race the chunk for the session, so wait for it directly
+    for i in 0.. {
+      assert!(i < 500);
+      if app.state.active_uploads.lock().await.contains(created["id"].as_str().unwrap()) {
+        break;
+      }
+      async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let res = app.patch("bob", &url).header("Upload-Offset", "0").body("").await.unwrap();
+    assert_eq!(res.status(), 423);
+    let res = app.delete("bob", &url).await.unwrap();
+    assert_eq!(res.status(), 423);
+    writer.write_all(b"hello").await.unwrap();
+    drop(writer);
+    let res = slow.await.unwrap();
+    assert_eq!(res.status(), 204);
+    assert_eq!(res.header("Upload-Offset").unwrap().as_str(), "5");
+
+    let mut res = app.get("bob", &url).await.unwrap();
+    let status: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!((status["offset"].as_u64(), status["complete"].as_bool()), (Some(5), Some(false)));
+
+    // without a length the overflow only shows once the upload is full, nothing of it is kept
+    let (mut writer, reader) = UnixStream::pair().unwrap();
+    writer.write_all(b"world!!").await.unwrap();
+    drop(writer);
+    let res = app.patch("bob", &url).header("Upload-Offset", "5").body(surf::Body::from_reader(BufReader::new(reader), None)).await.unwrap();
+    assert_eq!(res.status(), 413);
+    let res = app.patch("bob", &url).header("Upload-Offset", "5").body("world!!").await.unwrap();
+    assert_eq!(res.status(), 413);
+
+    let res = app.patch("bob", &url).header("Upload-Offset", "5").body("world").await.unwrap();
+    assert_eq!(res.status(), 204);
+    let mut res = app.get("bob", "cloud/files/test/big.bin").await.unwrap();
+    assert_eq!(res.body_string().await.unwrap(), "helloworld");
+    let res = app.get("bob", &url).await.unwrap();
+    assert_eq!(res.status(), 404);
+  }
+
+  #[async_std::test]
+  async fn keeps_every_acknowledged_chunk() {
+    let mut config = Config::default();
+    config.rate_limit.enabled = false;
+    let app = TestApp::with(config);
+    let mut res = app.post("bob", "cloud/uploads").body(json!({ "path": "test/race.bin", "length": 64 })).await.unwrap();
+    let created: serde_json::Value = res.body_json().await.unwrap();
+    let url = format!("cloud/uploads/{}", created["id"].as_str().unwrap());
+
+    // several chunks for the same offset at once, at most one of them may be taken
+    let mut acknowledged = 0;
+    while acknowledged < 64 {
+      let chunk = |app: &TestApp| app.patch("bob", &url).header("Upload-Offset", acknowledged.to_string()).body("ab").send();
+      let pending: Vec<_> = (0..8).map(|_| async_std::task::spawn(chunk(&app))).collect();
+      let mut statuses = Vec::new();
+      for res in pending {
+        statuses.push(res.await.unwrap().status());
+      }
+      assert!(statuses.iter().all(|s| [204, 409, 423].contains(&u16::from(*s))), "{:?}", statuses);
+      let taken = statuses.iter().filter(|s| **s == 204).count();
+      assert!(taken <= 1, "{:?}", statuses);
+      acknowledged += 2 * taken;
+      if acknowledged < 64 {
+        let mut res = app.get("bob", &url).await.unwrap();
+        let status: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(status["offset"], acknowledged);
+      }
+    }
+    let mut res = app.get("bob", "cloud/files/test/race.bin").await.unwrap();
+    assert_eq!(res.body_string().await.unwrap(), "ab".repeat(32));
+  }
+
+  #[async_std::test]
+  async fn drops_abandoned_uploads() {
+    let app = TestApp::new();
+    let mut urls = Vec::new();
+    for path in ["test/old.bin", "test/new.bin"] {
+      let mut res = app.post("bob", "cloud/uploads").body(json!({ "path": path, "length": 10 })).await.unwrap();
+      let created: serde_json::Value = res.body_json().await.unwrap();
+      urls.push((created["id"].as_str().unwrap().to_string(), format!("cloud/uploads/{}", created["id"].as_str().unwrap())));
+    }
+    let session = format!("{}/{}.json", app.state.config.cloud.upload_dir, urls[0].0);
+    let mut old: serde_json::Value = serde_json::from_slice(&std::fs::read(&session).unwrap()).unwrap();
+    old["created"] = json!(0);
+    std::fs::write(&session, old.to_string()).unwrap();
+    // a part without its session is kept until it is old enough as well
+    std::fs::write(format!("{}/1.part", app.state.config.cloud.upload_dir), "x").unwrap();
+
+    remove_abandoned(&app.state).await.unwrap();
+    let res = app.get("bob", &urls[0].1).await.unwrap();
+    assert_eq!(res.status(), 404);
+    assert!(!std::path::Path::new(&format!("{}/{}.part", app.state.config.cloud.upload_dir, urls[0].0)).exists());
+    let res = app.get("bob", &urls[1].1).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(std::path::Path::new(&format!("{}/1.part", app.state.config.cloud.upload_dir)).exists());
+  }
+}
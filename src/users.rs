@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tide::Request;
 
-use crate::{db::{create_record, delete_record, get_collection_records, modify_record, ModifyRecord}, permissions::{has_permissions, Permissions}};
+use crate::{audit, db::{create_record, delete_record, get_collection_page, get_collection_records, get_record, modify_record, new_record_id, ModifyRecord, PageQuery}, permissions::Permissions, state::AppState};
 
 #[derive(Deserialize, Debug, Serialize)]
 struct User {
@@ -13,9 +14,16 @@ struct User {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct UserCreate {
+  // chosen here, so the audit log can name the record like updates and deletes do
+  #[serde(skip_deserializing)]
+  id: String,
   name: String,
   username: String,
+  #[serde(default)]
   permissions: i32,
+  // expanded into permissions before saving, pocketbase only stores the bits
+  #[serde(default, skip_serializing)]
+  roles: Vec<String>,
   password: String,
   #[serde(rename = "passwordConfirm")]
   password_confirm: String,
@@ -32,7 +40,10 @@ struct UserUpdate {
   id: String,
   name: String,
   username: String,
+  #[serde(default)]
   permissions: i32,
+  #[serde(default, skip_serializing)]
+  roles: Vec<String>,
   password: Option<String>,
   #[serde(rename = "passwordConfirm")]
   password_confirm: Option<String>,
@@ -44,42 +55,55 @@ impl ModifyRecord for UserUpdate {
   } 
 }
 
-pub(crate) async fn get_users(req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Users as i32) {
-      return Ok(tide::Response::new(403));
-    }
-
-  let users = get_collection_records::<User>("users", None).await?;
-  let res = tide::Response::builder(200).body(tide::Body::from_json(&users)?);
-  Ok(res.build())
+pub(crate) async fn get_users(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let query: PageQuery = req.query()?;
+  let body = match query.page() {
+    Some((page, per_page)) => tide::Body::from_json(&get_collection_page::<User>(&state.db, "users", None, page, per_page).await?)?,
+    None => tide::Body::from_json(&get_collection_records::<User>(&state.db, "users", None).await?)?,
+  };
+  Ok(tide::Response::builder(200).body(body).build())
 }
 
-pub(crate) async fn create_user(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Users as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
-  let new_user: UserCreate = req.body_json().await?;
-  create_record("users", new_user).await?;
+pub(crate) async fn create_user(mut req: Request<AppState>) -> tide::Result {
+  let mut new_user: UserCreate = req.body_json().await?;
+  new_user.permissions = match Permissions::from_roles(&new_user.roles) {
+    Ok(p) => p.bits() | new_user.permissions,
+    Err(e) => return Ok(e.response()),
+  };
+  new_user.id = new_record_id();
+  let state = req.state();
+  // the password is left out of the log
+  let after = json!({ "id": new_user.id, "name": new_user.name, "username": new_user.username, "permissions": new_user.permissions });
+  let id = new_user.id.clone();
+  create_record(&state.db, "users", new_user).await?;
+  audit::record(&req, "user.create", &id, serde_json::Value::Null, after).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn delete_user(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Users as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
+pub(crate) async fn delete_user(mut req: Request<AppState>) -> tide::Result {
   let delete_user: UserDelete = req.body_json().await?;
-  delete_record("users", delete_user.id).await?;
+  let state = req.state();
+  let before = get_record::<User>(&state.db, "users", &delete_user.id).await?;
+  delete_record(&state.db, "users", delete_user.id.clone()).await?;
+  state.auth.invalidate_user(&delete_user.id).await;
+  audit::record(&req, "user.delete", &delete_user.id, serde_json::to_value(before)?, serde_json::Value::Null).await;
   Ok(tide::Response::new(200))
 }
 
-pub(crate) async fn update_user(mut req: Request<()>) -> tide::Result {
-  if !has_permissions(&req, Permissions::Users as i32) {
-    return Ok(tide::Response::new(403));
-  }
-
-  let modify_user: UserUpdate = req.body_json().await?;
-  modify_record("users", modify_user).await?;
+pub(crate) async fn update_user(mut req: Request<AppState>) -> tide::Result {
+  let mut modify_user: UserUpdate = req.body_json().await?;
+  modify_user.permissions = match Permissions::from_roles(&modify_user.roles) {
+    Ok(p) => p.bits() | modify_user.permissions,
+    Err(e) => return Ok(e.response()),
+  };
+  let state = req.state();
+  let id = modify_user.id.clone();
+  let before = get_record::<User>(&state.db, "users", &id).await?;
+  let after = json!({ "id": id, "name": modify_user.name, "username": modify_user.username, "permissions": modify_user.permissions, "password_changed": modify_user.password.is_some() });
+  modify_record(&state.db, "users", modify_user).await?;
+  // cached tokens still carry the old permissions
+  state.auth.invalidate_user(&id).await;
+  audit::record(&req, "user.update", &id, serde_json::to_value(before)?, after).await;
   Ok(tide::Response::new(200))
 }
\ No newline at end of file
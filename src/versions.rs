@@ -0,0 +1,313 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tide::Request;
+
+use crate::{audit, cloud::{check_permissions, file_size, serve_file, SYSTEM_DIR}, db::{create_record, delete_record, get_collection_records, modify_record, new_record_id, Filter, ModifyRecord}, permissions::request_user, quota::{record_usage, remove_usage}, state::AppState, storage::join};
+
+// every stored blob of a file has a record, the one with an empty key describes the current file
+#[derive(Serialize, Deserialize)]
+struct Version {
+  id: String,
+  path: String,
+  key: String,
+  size: u64,
+  user: String,
+  timestamp: i64,
+}
+
+// the id is chosen here, so the usage index can follow the record
+#[derive(Serialize)]
+struct VersionCreate {
+  id: String,
+  path: String,
+  key: String,
+  size: u64,
+  user: String,
+  timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct VersionUpdate {
+  id: String,
+  path: String,
+  key: String,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+  id: String,
+  timestamp: i64,
+  size: u64,
+  user: String,
+  current: bool,
+}
+
+#[derive(Deserialize)]
+struct VersionQuery {
+  id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VersionRestore {
+  id: String,
+}
+
+impl ModifyRecord for VersionUpdate {
+  fn id(&self) -> &String {
+    &self.id
+  }
+}
+
+pub(crate) async fn get_versions(req: Request<AppState>) -> tide::Result {
+  let state = req.state();
+  let (path, _) = match check_permissions(&req, false, false).await {
+    Ok(p) => p,
+    Err(r) => return Ok(r),
+  };
+
+  let VersionQuery { id } = req.query()?;
+  let mut versions = get_path_versions(state, &path).await?;
+
+  if let Some(id) = id {
+    let key = match versions.iter().find(|v| v.id == id) {
+      Some(v) if v.key.is_empty() => path.to_string(),
+      Some(v) => v.key.clone(),
+      None => return Ok(tide::Response::new(404)),
+    };
+    let metadata = match state.storage.metadata(&key).await {
+      Ok(m) if !m.dir => m,
+      _ => return Ok(tide::Response::new(410)),
+    };
+    return serve_file(&req, &key, &metadata).await;
+  }
+
+  let exists = state.storage.metadata(&path).await.is_ok_and(|m| !m.dir);
+  versions.sort_by_key(|v| -v.timestamp);
+  let versions: Vec<VersionInfo> = versions.into_iter()
+    .filter(|v| exists || !v.key.is_empty())
+    .map(|v| VersionInfo { current: v.key.is_empty(), id: v.id, timestamp: v.timestamp, size: v.size, user: v.user })
+    .collect();
+
+  Ok(tide::Response::builder(200).body(tide::Body::from_json(&versions)?).build())
+}
+
+pub(crate) async fn restore_version(mut req: Request<AppState>) -> tide::Result {
+  let (path, _) = match check_permissions(&req, false, true).await {
+    Ok(p) => p,
+    Err(r) => return Ok(r),
+  };
+
+  let VersionRestore { id } = req.body_json().await?;
+  let state = req.state();
+  let version = match get_path_versions(state, &path).await?.into_iter().find(|v| v.id == id) {
+    Some(v) if !v.key.is_empty() => v,
+    Some(_) => return Ok(tide::Response::new(200)),
+    None => return Ok(tide::Response::new(404)),
+  };
+
+  // the restored content is copied so the version stays in the history
+  let metadata = state.storage.metadata(&version.key).await?;
+  let archived = archive_current(state, &path).await?;
+  let reader = state.storage.read(&version.key).await?;
+  if let Err(e) = state.storage.write(&path, reader, metadata.size).await {
+    if let Some(key) = archived {
+      unarchive(state, &path, &key).await?;
+    }
+    return Err(e.into());
+  }
+
+  let user = request_user(&req)?;
+  record_current(state, &path, user, version.size).await?;
+  prune(state, &path).await?;
+  audit::record(&req, "version.restore", &path, serde_json::Value::Null, serde_json::json!({ "version": version.id, "timestamp": version.timestamp })).await;
+  Ok(tide::Response::new(200))
+}
+
+// moves the current content of a file into the history and returns the key it was moved to
+pub(crate) async fn archive_current(state: &AppState, path: &str) -> tide::Result<Option<String>> {
+  let metadata = match state.storage.metadata(path).await {
+    Ok(m) if !m.dir => m,
+    _ => return Ok(None),
+  };
+
+  let size = file_size(state, path, &metadata).await?;
+  let key = join(&format!("{}/versions", SYSTEM_DIR), &rand::random::<u128>().to_string());
+  state.storage.rename(path, &key).await?;
+
+  let current = get_collection_records::<Version>(&state.db, "cloud_versions", Some(Filter::eq("path", path).and(Filter::eq("key", "")))).await?;
+  match current.into_iter().next() {
+    Some(c) => modify_record(&state.db, "cloud_versions", VersionUpdate { id: c.id, path: c.path, key: key.clone() }).await?,
+    None => {
+      // files uploaded before versioning have no record, so their uploader is unknown
+      let timestamp = metadata.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+      let version = VersionCreate { id: new_record_id(), path: path.to_string(), key: key.clone(), size, user: String::new(), timestamp };
+      create_version(state, version).await?
+    },
+  }
+
+  Ok(Some(key))
+}
+
+pub(crate) async fn unarchive(state: &AppState, path: &str, key: &str) -> tide::Result<()> {
+  state.storage.rename(key, path).await?;
+  let archived = get_collection_records::<Version>(&state.db, "cloud_versions", Some(Filter::eq("key", key))).await?;
+  for version in archived {
+    modify_record(&state.db, "cloud_versions", VersionUpdate { id: version.id, path: version.path, key: String::new() }).await?;
+  }
+  Ok(())
+}
+
+pub(crate) async fn record_current(state: &AppState, path: &str, user: &str, size: u64) -> tide::Result<()> {
+  // a current record can be left behind when the file was deleted
+  let stale = get_collection_records::<Version>(&state.db, "cloud_versions", Some(Filter::eq("path", path).and(Filter::eq("key", "")))).await?;
+  for version in stale {
+    delete_record(&state.db, "cloud_versions", version.id.clone()).await?;
+    remove_usage(state, &version.id).await;
+  }
+
+  let timestamp = chrono::Utc::now().timestamp();
+  create_version(state, VersionCreate { id: new_record_id(), path: path.to_string(), key: String::new(), size, user: user.to_string(), timestamp }).await
+}
+
+async fn create_version(state: &AppState, version: VersionCreate) -> tide::Result<()> {
+  create_record(&state.db, "cloud_versions", &version).await?;
+  record_usage(state, &version.id, &version.user, &version.path, version.size).await;
+  Ok(())
+}
+
+pub(crate) async fn move_versions(state: &AppState, from: &str, to: &str) -> tide::Result<()> {
+  let versions = get_collection_records::<Version>(&state.db, "cloud_versions", Some(path_or_below(from))).await?;
+  for version in versions {
+    let path = format!("{}{}", to, &version.path[from.len()..]);
+    modify_record(&state.db, "cloud_versions", VersionUpdate { id: version.id.clone(), path: path.clone(), key: version.key }).await?;
+    record_usage(state, &version.id, &version.user, &path, version.size).await;
+  }
+  Ok(())
+}
+
+// removes the whole history of a path and everything below it, the current files are left to the caller
+pub(crate) async fn delete_versions(state: &AppState, path: &str) -> tide::Result<()> {
+  let versions = get_collection_records::<Version>(&state.db, "cloud_versions", Some(path_or_below(path))).await?;
+  for version in versions {
+    if version.key.is_empty() {
+      delete_record(&state.db, "cloud_versions", version.id.clone()).await?;
+      remove_usage(state, &version.id).await;
+    } else {
+      delete_version(state, version).await?;
+    }
+  }
+  Ok(())
+}
+
+pub(crate) async fn prune(state: &AppState, path: &str) -> tide::Result<()> {
+  let mut versions: Vec<Version> = get_path_versions(state, path).await?.into_iter().filter(|v| !v.key.is_empty()).collect();
+  versions.sort_by_key(|v| -v.timestamp);
+
+  let cutoff = max_age_cutoff(state);
+  for (i, version) in versions.into_iter().enumerate() {
+    if i >= state.config.cloud.max_versions || cutoff.is_some_and(|c| version.timestamp < c) {
+      delete_version(state, version).await?;
+    }
+  }
+  Ok(())
+}
+
+pub(crate) async fn prune_expired(state: AppState) {
+  loop {
+    if let Some(cutoff) = max_age_cutoff(&state) {
+      let filter = Filter::ne("key", "").and(Filter::lt("timestamp", cutoff));
+      match get_collection_records::<Version>(&state.db, "cloud_versions", Some(filter)).await {
+        Ok(versions) => {
+          for version in versions {
+            if let Err(e) = delete_version(&state, version).await {
+              tide::log::error!("Failed to prune version: {}", e);
+            }
+          }
+        },
+        Err(e) => tide::log::error!("Failed to load expired versions: {}", e),
+      }
+    }
+    async_std::task::sleep(Duration::from_secs(60 * 60)).await;
+  }
+}
+
+async fn delete_version(state: &AppState, version: Version) -> tide::Result<()> {
+  if let Err(e) = state.storage.delete_file(&version.key).await {
+    if e.kind() != std::io::ErrorKind::NotFound {
+      return Err(e.into());
+    }
+  }
+  delete_record(&state.db, "cloud_versions", version.id.clone()).await?;
+  remove_usage(state, &version.id).await;
+  Ok(())
+}
+
+async fn get_path_versions(state: &AppState, path: &str) -> tide::Result<Vec<Version>> {
+  get_collection_records::<Version>(&state.db, "cloud_versions", Some(Filter::eq("path", path))).await
+}
+
+fn path_or_below(path: &str) -> Filter {
+  Filter::eq("path", path).or(Filter::starts_with("path", &format!("{}/", path)))
+}
+
+fn max_age_cutoff(state: &AppState) -> Option<i64> {
+  match state.config.cloud.version_max_age {
+    0 => None,
+    days => Some(chrono::Utc::now().timestamp() - days as i64 * 24 * 60 * 60),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{config::Config, test_support::{Records, TestApp}};
+
+  #[async_std::test]
+  async fn keeps_a_bounded_history() {
+    let mut config = Config::default();
+    config.cloud.max_versions = 2;
+    let records = Records::default();
+    // older than the maximum age, its blob is gone already
+    let expired = json!({ "id": "expired", "path": "test/doc.txt", "key": ".cloud/versions/1", "size": 3, "user": "bob", "timestamp": 1 });
+    records.lock().unwrap().push(("cloud_versions".to_string(), expired));
+    let app = TestApp::on(config, records.clone());
+
+    for content in ["one", "two", "three", "four"] {
+      let res = app.post("bob", "cloud/files/test/doc.txt").body(content).await.unwrap();
+      assert_eq!(res.status(), 200);
+    }
+    assert!(!records.lock().unwrap().iter().any(|(_, r)| r["id"] == "expired"));
+
+    let mut res = app.get("bob", "cloud/versions/test/doc.txt").await.unwrap();
+    let versions: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!(versions.len(), 3);
+    let current: Vec<_> = versions.iter().filter(|v| v["current"] == true).collect();
+    assert_eq!((current.len(), &current[0]["size"], &current[0]["user"]), (1, &json!(4), &json!("bob")));
+
+    // every version that is listed can still be read
+    let mut archived = Vec::new();
+    for version in versions.iter().filter(|v| v["current"] == false) {
+      let mut res = app.get("bob", format!("cloud/versions/test/doc.txt?id={}", version["id"].as_str().unwrap())).await.unwrap();
+      assert_eq!(res.status(), 200);
+      archived.push((version["id"].as_str().unwrap().to_string(), res.body_string().await.unwrap()));
+    }
+    assert!(archived.iter().all(|(_, content)| ["one", "two", "three"].contains(&content.as_str())));
+
+    let (id, content) = &archived[0];
+    let res = app.post("bob", "cloud/versions/test/doc.txt").body(json!({ "id": id })).await.unwrap();
+    assert_eq!(res.status(), 200);
+    let mut res = app.get("bob", "cloud/files/test/doc.txt").await.unwrap();
+    assert_eq!(&res.body_string().await.unwrap(), content);
+    let mut res = app.get("bob", "cloud/versions/test/doc.txt").await.unwrap();
+    let versions: Vec<serde_json::Value> = res.body_json().await.unwrap();
+    assert_eq!(versions.len(), 3);
+    assert_eq!(records.lock().unwrap().iter().filter(|(c, _)| c == "cloud_versions").count(), 3);
+
+    let res = app.post("bob", "cloud/versions/test/doc.txt").body(json!({ "id": "unknown" })).await.unwrap();
+    assert_eq!(res.status(), 404);
+    let res = app.get("bob", "cloud/versions/test/other.txt").await.unwrap();
+    assert_eq!(res.status(), 200);
+  }
+}